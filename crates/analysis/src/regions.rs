@@ -0,0 +1,83 @@
+use crate::statistics::StreamingStatistics;
+use std::collections::BTreeMap;
+use thor_shared::{RegionResult, RegionStatistics};
+
+#[derive(Default)]
+struct RegionSeries {
+    duration_seconds: StreamingStatistics,
+    energy: BTreeMap<&'static str, StreamingStatistics>,
+    joules_per_unit: BTreeMap<&'static str, StreamingStatistics>,
+    units_per_joule: Option<StreamingStatistics>,
+}
+
+/// Collects the results of repeated runs of each region id.
+#[derive(Default)]
+pub struct RegionAggregator {
+    // Keyed by the region id and its attributes
+    regions: BTreeMap<(String, Vec<(String, String)>), RegionSeries>,
+}
+
+impl RegionAggregator {
+    pub fn add(&mut self, region: &RegionResult) {
+        let series = self
+            .regions
+            .entry((region.id.clone(), region.attributes.clone()))
+            .or_default();
+        series
+            .duration_seconds
+            .push(region.duration_nanos as f64 / 1_000_000_000.0);
+        for (domain, joules) in region.energy.domains() {
+            series.energy.entry(domain).or_default().push(joules);
+        }
+
+        if let Some(joules_per_unit) = &region.joules_per_unit {
+            for (domain, joules) in joules_per_unit.domains() {
+                series
+                    .joules_per_unit
+                    .entry(domain)
+                    .or_default()
+                    .push(joules);
+            }
+        }
+        if let Some(units_per_joule) = region.units_per_joule {
+            series
+                .units_per_joule
+                .get_or_insert_with(StreamingStatistics::default)
+                .push(units_per_joule);
+        }
+    }
+
+    /// Statistics of every region id and attributes seen so far, ordered by id.
+    pub fn statistics(&self) -> Vec<RegionStatistics> {
+        self.regions
+            .iter()
+            .map(|((id, attributes), series)| RegionStatistics {
+                id: id.clone(),
+                attributes: attributes.clone(),
+                duration_seconds: series.duration_seconds.summary(),
+                energy: series
+                    .energy
+                    .iter()
+                    .map(|(domain, statistics)| (domain.to_string(), statistics.summary()))
+                    .collect(),
+                joules_per_unit: series
+                    .joules_per_unit
+                    .iter()
+                    .map(|(domain, statistics)| (domain.to_string(), statistics.summary()))
+                    .collect(),
+                units_per_joule: series
+                    .units_per_joule
+                    .as_ref()
+                    .map(StreamingStatistics::summary),
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.regions.clear();
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use thor_shared::Summary;
+
+// Relative width of the histogram buckets, percentiles are accurate to about 1%
+const HISTOGRAM_PRECISION: f64 = 0.01;
+
+// Number of values kept for bootstrapping, and the number of resamples drawn from them
+const RESERVOIR_SIZE: usize = 1000;
+const BOOTSTRAP_RESAMPLES: usize = 500;
+
+/// Statistics over a stream of values, using constant memory per value range.
+#[derive(Debug, Clone)]
+pub struct StreamingStatistics {
+    count: u64,
+    mean: f64,
+    // Sum of squared differences from the mean (Welford's algorithm)
+    m2: f64,
+    min: f64,
+    max: f64,
+    histogram: LogHistogram,
+    reservoir: Vec<f64>,
+    rng: SplitMix64,
+}
+
+impl Default for StreamingStatistics {
+    fn default() -> Self {
+        StreamingStatistics {
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            histogram: LogHistogram::default(),
+            reservoir: Vec::new(),
+            rng: SplitMix64(0x7468_6f72),
+        }
+    }
+}
+
+impl StreamingStatistics {
+    pub fn push(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.histogram.push(value);
+
+        // Reservoir sampling keeps a uniform sample of all values seen
+        if self.reservoir.len() < RESERVOIR_SIZE {
+            self.reservoir.push(value);
+        } else {
+            let index = self.rng.next_below(self.count);
+            if index < RESERVOIR_SIZE as u64 {
+                self.reservoir[index as usize] = value;
+            }
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The sample variance.
+    pub fn variance(&self) -> f64 {
+        if self.count > 1 {
+            self.m2 / (self.count - 1) as f64
+        } else {
+            0.0
+        }
+    }
+
+    pub fn percentile(&self, percentile: f64) -> f64 {
+        self.histogram
+            .percentile(percentile, self.count)
+            .clamp(self.min, self.max)
+    }
+
+    /// Bootstrapped confidence interval of the mean at the given level, e.g. 0.95.
+    pub fn mean_confidence_interval(&self, level: f64) -> Option<(f64, f64)> {
+        if self.count < 2 {
+            return None;
+        }
+
+        let mut rng = self.rng.clone();
+        let mut means: Vec<f64> = (0..BOOTSTRAP_RESAMPLES)
+            .map(|_| {
+                let sum: f64 = (0..self.reservoir.len())
+                    .map(|_| self.reservoir[rng.next_below(self.reservoir.len() as u64) as usize])
+                    .sum();
+                sum / self.reservoir.len() as f64
+            })
+            .collect();
+        means.sort_by(f64::total_cmp);
+
+        let tail = (1.0 - level) / 2.0;
+        let lower = means[((BOOTSTRAP_RESAMPLES - 1) as f64 * tail).round() as usize];
+        let upper = means[((BOOTSTRAP_RESAMPLES - 1) as f64 * (1.0 - tail)).round() as usize];
+
+        // The resampled means spread as for the size of the reservoir, so once it holds a sample
+        // of the values the interval is narrowed to all of them, around the mean of all of them
+        let reservoir_mean = self.reservoir.iter().sum::<f64>() / self.reservoir.len() as f64;
+        let scale = (self.reservoir.len() as f64 / self.count as f64).sqrt();
+        Some((
+            self.mean + (lower - reservoir_mean) * scale,
+            self.mean + (upper - reservoir_mean) * scale,
+        ))
+    }
+
+    pub fn summary(&self) -> Summary {
+        let variance = self.variance();
+        Summary {
+            count: self.count,
+            mean: self.mean,
+            variance,
+            std_dev: variance.sqrt(),
+            min: self.min,
+            max: self.max,
+            p50: self.percentile(0.5),
+            p90: self.percentile(0.9),
+            p99: self.percentile(0.99),
+            mean_confidence_interval: self.mean_confidence_interval(0.95),
+        }
+    }
+}
+
+// Histogram with logarithmically sized buckets, so it stays small for any range of values.
+// Negative values get buckets mirrored around zero.
+#[derive(Debug, Clone, Default)]
+struct LogHistogram {
+    buckets: BTreeMap<i64, u64>,
+}
+
+impl LogHistogram {
+    fn push(&mut self, value: f64) {
+        *self.buckets.entry(bucket_index(value)).or_default() += 1;
+    }
+
+    fn percentile(&self, percentile: f64, count: u64) -> f64 {
+        let rank = ((count as f64 * percentile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (&index, &bucket_count) in &self.buckets {
+            seen += bucket_count;
+            if seen >= rank {
+                return bucket_value(index);
+            }
+        }
+        0.0
+    }
+}
+
+// Values smaller than this all go into the zero bucket
+const HISTOGRAM_MIN_VALUE: f64 = 1e-12;
+
+fn bucket_index(value: f64) -> i64 {
+    if value.abs() < HISTOGRAM_MIN_VALUE {
+        return 0;
+    }
+    // Offset by one so index 0 is free for zero
+    let index = ((value.abs() / HISTOGRAM_MIN_VALUE).ln() / HISTOGRAM_PRECISION.ln_1p()) as i64 + 1;
+    if value < 0.0 {
+        -index
+    } else {
+        index
+    }
+}
+
+// The middle of a bucket
+fn bucket_value(index: i64) -> f64 {
+    if index == 0 {
+        return 0.0;
+    }
+    let magnitude =
+        HISTOGRAM_MIN_VALUE * (HISTOGRAM_PRECISION.ln_1p() * (index.abs() as f64 - 0.5)).exp();
+    if index < 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+// Small deterministic random number generator, good enough for resampling
+#[derive(Debug, Clone)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn next_below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
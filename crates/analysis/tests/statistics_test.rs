@@ -0,0 +1,163 @@
+use thor_analysis::{regions::RegionAggregator, statistics::StreamingStatistics};
+use thor_lib::{AmdRaplRegistersJoules, RaplMeasurementJoules};
+use thor_shared::RegionResult;
+
+fn region(id: &str, duration_nanos: u128, pkg: f64) -> RegionResult {
+    let energy = RaplMeasurementJoules::AMD(AmdRaplRegistersJoules {
+        core: pkg / 2.0,
+        pkg,
+    });
+    RegionResult {
+        id: id.to_string(),
+        attributes: Vec::new(),
+        process_id: 1,
+        thread_id: 1,
+        start_timestamp: 0,
+        stop_timestamp: duration_nanos,
+        duration_nanos,
+        exclusive_energy: energy.clone(),
+        uncertainty: Some(energy.map(|_| 0.0)),
+        reliable: true,
+        overhead_corrected_energy: None,
+        work_count: None,
+        joules_per_unit: None,
+        units_per_joule: None,
+        energy,
+        parent_path: Vec::new(),
+        synthetic_stop: false,
+        energy_above_baseline: None,
+        baseline: None,
+        cpu_time_share: None,
+        attributed_energy: None,
+        start_cpu: None,
+        stop_cpu: None,
+        single_core: None,
+        core_energy: None,
+    }
+}
+
+#[test]
+fn test_mean_variance_and_extremes() {
+    let mut statistics = StreamingStatistics::default();
+    for value in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0] {
+        statistics.push(value);
+    }
+
+    let summary = statistics.summary();
+    assert_eq!(summary.count, 8);
+    assert!((summary.mean - 5.0).abs() < 1e-9);
+    assert!((summary.variance - 32.0 / 7.0).abs() < 1e-9);
+    assert_eq!(summary.min, 2.0);
+    assert_eq!(summary.max, 9.0);
+}
+
+#[test]
+fn test_percentiles_within_histogram_precision() {
+    let mut statistics = StreamingStatistics::default();
+    for value in 1..=1000 {
+        statistics.push(value as f64);
+    }
+
+    let summary = statistics.summary();
+    assert!((summary.p50 - 500.0).abs() / 500.0 < 0.01);
+    assert!((summary.p90 - 900.0).abs() / 900.0 < 0.01);
+    assert!((summary.p99 - 990.0).abs() / 990.0 < 0.01);
+}
+
+#[test]
+fn test_confidence_interval_contains_mean() {
+    let mut statistics = StreamingStatistics::default();
+    assert!(statistics.mean_confidence_interval(0.95).is_none());
+
+    for value in 0..5000 {
+        statistics.push((value % 10) as f64);
+    }
+
+    let (lower, upper) = statistics.mean_confidence_interval(0.95).unwrap();
+    assert!(lower < upper);
+    assert!(lower < 4.5 && 4.5 < upper);
+    assert!(upper - lower < 1.0);
+}
+
+#[test]
+fn test_confidence_interval_narrows_beyond_the_reservoir() {
+    let mut statistics = StreamingStatistics::default();
+    for value in 0..100_000 {
+        statistics.push((value % 10) as f64);
+    }
+
+    // The normal interval of the mean of all values, which the bootstrap should be close to
+    let half_width = 1.96 * (statistics.variance() / statistics.count() as f64).sqrt();
+    let (lower, upper) = statistics.mean_confidence_interval(0.95).unwrap();
+    assert!(lower < 4.5 && 4.5 < upper);
+    assert!((upper - lower) > half_width);
+    assert!((upper - lower) < half_width * 3.0);
+}
+
+#[test]
+fn test_aggregate_regions_by_id() {
+    let mut aggregator = RegionAggregator::default();
+    aggregator.add(&region("b", 1_000_000_000, 10.0));
+    aggregator.add(&region("a", 2_000_000_000, 4.0));
+    aggregator.add(&region("a", 4_000_000_000, 8.0));
+
+    let statistics = aggregator.statistics();
+    assert_eq!(statistics.len(), 2);
+    assert_eq!(statistics[0].id, "a");
+    assert_eq!(statistics[0].duration_seconds.count, 2);
+    assert!((statistics[0].duration_seconds.mean - 3.0).abs() < 1e-9);
+
+    let (domain, pkg) = statistics[0]
+        .energy
+        .iter()
+        .find(|(domain, _)| domain == "pkg")
+        .unwrap();
+    assert_eq!(domain, "pkg");
+    assert!((pkg.mean - 6.0).abs() < 1e-9);
+
+    aggregator.clear();
+    assert!(aggregator.is_empty());
+}
+
+#[test]
+fn test_aggregate_energy_per_work_unit() {
+    let mut aggregator = RegionAggregator::default();
+    for (work_count, pkg) in [(10, 5.0), (20, 20.0)] {
+        let mut region = region("sort", 1_000_000_000, pkg);
+        region.work_count = Some(work_count);
+        region.joules_per_unit = Some(region.energy.map(|joules| joules / work_count as f64));
+        region.units_per_joule = Some(work_count as f64 / pkg);
+        aggregator.add(&region);
+    }
+    aggregator.add(&region("sort", 1_000_000_000, 1.0));
+
+    let statistics = aggregator.statistics();
+    let (_, pkg_per_unit) = statistics[0]
+        .joules_per_unit
+        .iter()
+        .find(|(domain, _)| domain == "pkg")
+        .unwrap();
+    assert_eq!(pkg_per_unit.count, 2);
+    assert!((pkg_per_unit.mean - 0.75).abs() < 1e-9);
+
+    let units_per_joule = statistics[0].units_per_joule.as_ref().unwrap();
+    assert_eq!(units_per_joule.count, 2);
+    assert!((units_per_joule.mean - 1.5).abs() < 1e-9);
+}
+
+#[test]
+fn test_aggregate_regions_by_attributes() {
+    let mut aggregator = RegionAggregator::default();
+    for size in ["small", "large", "small"] {
+        let mut region = region("sort", 1_000_000_000, 1.0);
+        region.attributes = vec![("size".to_string(), size.to_string())];
+        aggregator.add(&region);
+    }
+
+    let statistics = aggregator.statistics();
+    assert_eq!(statistics.len(), 2);
+    assert_eq!(statistics[0].attributes[0].1, "large");
+    assert_eq!(statistics[0].duration_seconds.count, 1);
+    assert_eq!(statistics[1].attributes[0].1, "small");
+    assert_eq!(statistics[1].duration_seconds.count, 2);
+}
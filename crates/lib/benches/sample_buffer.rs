@@ -0,0 +1,154 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use crossbeam::queue::SegQueue;
+use rangemap::RangeMap;
+use thor_lib::{
+    sample_buffer::{sample_ring_buffer, SampleReader, SampleWriter},
+    IntelRaplRegisters, RaplMeasurement,
+};
+
+// Mirrors the default thor-server.toml: 5000 ms of samples taken every 50 µs
+const MAX_SAMPLE_AGE_MILLIS: u128 = 5000;
+const SAMPLING_INTERVAL_MICROS: u64 = 50;
+const SAMPLE_COUNT: usize =
+    (MAX_SAMPLE_AGE_MILLIS * 1000 / SAMPLING_INTERVAL_MICROS as u128) as usize;
+const SAMPLE_SPACING_NANOS: u128 = SAMPLING_INTERVAL_MICROS as u128 * 1000;
+// A realistic UNIX epoch timestamp in nanoseconds
+const FIRST_TIMESTAMP: u128 = 1_700_000_000_000_000_000;
+
+/// The SegQueue + RangeMap sampler used by thor-server before the ring buffer, kept as a baseline.
+struct LegacySampler {
+    queue: SegQueue<(RaplMeasurement, u128)>,
+    range_map: RangeMap<u128, (RaplMeasurement, u32)>,
+    pkg_overflow: u32,
+    last_pkg: u64,
+}
+
+impl LegacySampler {
+    fn new() -> LegacySampler {
+        LegacySampler {
+            queue: SegQueue::new(),
+            range_map: RangeMap::new(),
+            pkg_overflow: 0,
+            last_pkg: 0,
+        }
+    }
+
+    fn update_range_map(&mut self, timestamp: u128) {
+        while let Some((measurement, time)) = self.queue.pop() {
+            if let RaplMeasurement::Intel(ref intel_rapl_registers) = measurement {
+                if self.last_pkg > intel_rapl_registers.pkg {
+                    self.pkg_overflow += 1;
+                }
+                self.last_pkg = intel_rapl_registers.pkg;
+            }
+
+            self.range_map.insert(
+                time..time + (SAMPLING_INTERVAL_MICROS * 1000 + 20_000_000) as u128,
+                (measurement, self.pkg_overflow),
+            );
+        }
+
+        self.range_map
+            .remove(0..timestamp - (MAX_SAMPLE_AGE_MILLIS * 1_000_000));
+    }
+}
+
+fn measurement(index: usize) -> RaplMeasurement {
+    let value = index as u64;
+    RaplMeasurement::Intel(IntelRaplRegisters {
+        pp0: value,
+        pp1: value,
+        pkg: value,
+        dram: value,
+    })
+}
+
+fn timestamp(index: usize) -> u128 {
+    FIRST_TIMESTAMP + index as u128 * SAMPLE_SPACING_NANOS
+}
+
+fn filled_ring_buffer() -> (SampleWriter, SampleReader) {
+    let (mut writer, reader) = sample_ring_buffer(SAMPLE_COUNT);
+    for index in 0..SAMPLE_COUNT {
+        writer.push(timestamp(index), &measurement(index));
+    }
+    (writer, reader)
+}
+
+fn filled_legacy_sampler() -> LegacySampler {
+    let mut sampler = LegacySampler::new();
+    for index in 0..SAMPLE_COUNT {
+        sampler.queue.push((measurement(index), timestamp(index)));
+    }
+    sampler.update_range_map(timestamp(SAMPLE_COUNT));
+    sampler
+}
+
+fn insertion(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insertion");
+
+    group.bench_function("ring_buffer", |b| {
+        let (mut writer, _reader) = filled_ring_buffer();
+        let mut index = SAMPLE_COUNT;
+        b.iter(|| {
+            writer.push(timestamp(index), &measurement(index));
+            index += 1;
+        });
+    });
+
+    group.bench_function("segqueue_rangemap", |b| {
+        let mut sampler = filled_legacy_sampler();
+        let mut index = SAMPLE_COUNT;
+        b.iter(|| {
+            sampler.queue.push((measurement(index), timestamp(index)));
+            sampler.update_range_map(timestamp(index));
+            index += 1;
+        });
+    });
+
+    group.finish();
+}
+
+fn lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+
+    // Look up timestamps falling between samples, spread over the whole window
+    let lookups: Vec<u128> = (0..1024)
+        .map(|i| timestamp(1 + i * 97 % (SAMPLE_COUNT - 1)) + SAMPLE_SPACING_NANOS / 2)
+        .collect();
+
+    group.bench_function("ring_buffer", |b| {
+        let (_writer, reader) = filled_ring_buffer();
+        let mut lookup = lookups.iter().cycle();
+        b.iter(|| black_box(reader.find(*lookup.next().unwrap())));
+    });
+
+    group.bench_function("segqueue_rangemap", |b| {
+        let sampler = filled_legacy_sampler();
+        let mut lookup = lookups.iter().cycle();
+        b.iter(|| black_box(sampler.range_map.get(lookup.next().unwrap()).cloned()));
+    });
+
+    group.bench_function("segqueue_rangemap_with_update", |b| {
+        b.iter_batched_ref(
+            || {
+                let sampler = filled_legacy_sampler();
+                // A cycle's worth of samples waiting in the queue, as in the server loop
+                for index in SAMPLE_COUNT..SAMPLE_COUNT + 5000 {
+                    sampler.queue.push((measurement(index), timestamp(index)));
+                }
+                sampler
+            },
+            |sampler| {
+                sampler.update_range_map(lookups[0]);
+                black_box(sampler.range_map.get(&lookups[0]).cloned())
+            },
+            BatchSize::LargeInput,
+        );
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, insertion, lookup);
+criterion_main!(benches);
@@ -1,6 +1,8 @@
 use sysinfo::System;
 
 fn main() {
+    println!("cargo:rustc-check-cfg=cfg(amd, intel)");
+
     let sys = System::new_all();
     let cpu = sys.cpus().first().expect("failed getting CPU").vendor_id();
     match cpu {
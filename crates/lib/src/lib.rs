@@ -4,6 +4,8 @@ use serde::{Deserialize, Serialize};
 use std::sync::Once;
 use thiserror::Error;
 
+pub mod sample_buffer;
+
 // Use the OS specific implementation
 #[cfg(target_os = "linux")]
 mod os_linux;
@@ -65,6 +67,72 @@ pub enum RaplMeasurementJoules {
     AMD(AmdRaplRegistersJoules),
 }
 
+impl RaplMeasurementJoules {
+    /// The name and value of every domain.
+    pub fn domains(&self) -> Vec<(&'static str, f64)> {
+        match self {
+            RaplMeasurementJoules::Intel(intel) => vec![
+                ("pp0", intel.pp0),
+                ("pp1", intel.pp1),
+                ("pkg", intel.pkg),
+                ("dram", intel.dram),
+            ],
+            RaplMeasurementJoules::AMD(amd) => vec![("core", amd.core), ("pkg", amd.pkg)],
+        }
+    }
+
+    /// Energy of the package domain, which is available on every vendor.
+    pub fn pkg(&self) -> f64 {
+        match self {
+            RaplMeasurementJoules::Intel(intel) => intel.pkg,
+            RaplMeasurementJoules::AMD(amd) => amd.pkg,
+        }
+    }
+
+    /// Apply a function to the value of every domain.
+    pub fn map(&self, f: impl Fn(f64) -> f64) -> RaplMeasurementJoules {
+        match self {
+            RaplMeasurementJoules::Intel(intel) => {
+                RaplMeasurementJoules::Intel(IntelRaplRegistersJoules {
+                    pp0: f(intel.pp0),
+                    pp1: f(intel.pp1),
+                    pkg: f(intel.pkg),
+                    dram: f(intel.dram),
+                })
+            }
+            RaplMeasurementJoules::AMD(amd) => RaplMeasurementJoules::AMD(AmdRaplRegistersJoules {
+                core: f(amd.core),
+                pkg: f(amd.pkg),
+            }),
+        }
+    }
+
+    /// Combine the value of every domain with the same domain of another measurement.
+    pub fn zip_with(
+        &self,
+        other: &RaplMeasurementJoules,
+        f: impl Fn(f64, f64) -> f64,
+    ) -> RaplMeasurementJoules {
+        match (self, other) {
+            (RaplMeasurementJoules::Intel(a), RaplMeasurementJoules::Intel(b)) => {
+                RaplMeasurementJoules::Intel(IntelRaplRegistersJoules {
+                    pp0: f(a.pp0, b.pp0),
+                    pp1: f(a.pp1, b.pp1),
+                    pkg: f(a.pkg, b.pkg),
+                    dram: f(a.dram, b.dram),
+                })
+            }
+            (RaplMeasurementJoules::AMD(a), RaplMeasurementJoules::AMD(b)) => {
+                RaplMeasurementJoules::AMD(AmdRaplRegistersJoules {
+                    core: f(a.core, b.core),
+                    pkg: f(a.pkg, b.pkg),
+                })
+            }
+            _ => panic!("RAPL measurements do not match"),
+        }
+    }
+}
+
 #[bitfield(u64)]
 #[derive(PartialEq, Eq)] // <- Attributes after `bitfield` are carried over
 struct IntelRaplPowerUnits {
@@ -112,7 +180,7 @@ pub fn convert_rapl_msr_register_to_joules(
     curr_measurement: RaplMeasurement,
 ) -> RaplMeasurementJoules {
     // Get the power unit
-    let power_unit = *RAPL_POWER_UNITS.get_or_init(|| read_rapl_msr_power_unit());
+    let power_unit = *RAPL_POWER_UNITS.get_or_init(read_rapl_msr_power_unit);
 
     // Shift the power unit by 8 bits and then AND it with 0x1f
     let joule_unit = (power_unit >> 8) & 0x1f;
@@ -193,6 +261,53 @@ fn get_energy_unit() -> f64 {
     0.5f64.powi(power_unit.energy_status_units() as i32)
 }
 
+/// Read the energy counter of a single CPU core in joules. Only AMD CPUs have per-core counters.
+pub fn read_core_energy(cpu: u32) -> Option<f64> {
+    #[cfg(amd)]
+    {
+        use self::amd::AMD_MSR_CORE_ENERGY;
+        #[cfg(target_os = "linux")]
+        use self::os_linux::read_msr_on_cpu;
+        #[cfg(target_os = "windows")]
+        use self::os_windows::read_msr_on_cpu;
+
+        RAPL_INIT.call_once(|| {
+            // Run the OS specific rapl_init function, to enable reading MSR registers
+            rapl_init();
+        });
+        let energy_units = *ENERGY_UNITS.get_or_init(get_energy_unit);
+
+        read_msr_on_cpu(cpu, AMD_MSR_CORE_ENERGY)
+            .ok()
+            .map(|core| core as f64 * energy_units)
+    }
+
+    #[cfg(not(amd))]
+    {
+        let _ = cpu;
+        None
+    }
+}
+
+/// The vendor of the CPU the RAPL registers are read from, the library is built for one vendor.
+pub fn cpu_vendor() -> &'static str {
+    if cfg!(amd) {
+        "AMD"
+    } else {
+        "Intel"
+    }
+}
+
+/// Energy in joules represented by one increment of a RAPL energy counter.
+pub fn energy_unit_joules() -> f64 {
+    *ENERGY_UNITS.get_or_init(get_energy_unit)
+}
+
+/// Energy in joules represented by one full wrap of a 32-bit RAPL energy counter.
+pub fn counter_wrap_joules() -> f64 {
+    (u32::MAX as f64 + 1.0) * energy_unit_joules()
+}
+
 pub fn convert_to_joules(measurement: RaplMeasurement) -> RaplMeasurementJoules {
     let energy_units = *ENERGY_UNITS.get_or_init(get_energy_unit);
 
@@ -1,11 +1,13 @@
 use super::RaplError;
 use once_cell::sync::OnceCell;
-use std::{fs::File, os::unix::prelude::FileExt};
+use std::{collections::HashMap, fs::File, os::unix::prelude::FileExt, sync::Mutex};
 
 // Running it for now: sudo ./target/debug/rapl-bin
 
 static CPU0_MSR_FD: OnceCell<File> = OnceCell::new();
 
+static CPU_MSR_FDS: OnceCell<Mutex<HashMap<u32, File>>> = OnceCell::new();
+
 pub fn rapl_init() {}
 
 // https://github.com/greensoftwarelab/Energy-Languages/blob/master/RAPL/rapl.c#L14
@@ -24,3 +26,21 @@ pub fn read_msr(msr_offset: u64) -> Result<u64, RaplError> {
 
     Ok(u64::from_le_bytes(output_data))
 }
+
+// Read an MSR of a specific CPU, used for the per-core energy counters on AMD
+#[allow(dead_code)]
+pub fn read_msr_on_cpu(cpu: u32, msr_offset: u64) -> Result<u64, RaplError> {
+    let mut fds = CPU_MSR_FDS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap();
+    let f = match fds.entry(cpu) {
+        std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+        std::collections::hash_map::Entry::Vacant(entry) => entry.insert(open_msr(cpu)?),
+    };
+
+    let mut output_data: [u8; 8] = [0; 8];
+    f.read_at(&mut output_data, msr_offset)?;
+
+    Ok(u64::from_le_bytes(output_data))
+}
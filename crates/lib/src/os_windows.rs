@@ -124,6 +124,12 @@ pub fn read_msr(msr: u64) -> Result<u64, RaplError> {
     Ok(u64::from_le_bytes(output_data))
 }
 
+// The driver reads MSRs on the CPU the calling thread runs on, so reading a specific CPU is not supported
+#[allow(dead_code)]
+pub fn read_msr_on_cpu(_cpu: u32, _msr: u64) -> Result<u64, RaplError> {
+    Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+}
+
 /*
 // Experimental. This was not a great success because Windows takes too long deleting + recreating the driver
 // TODO: Consider documenting this or revisiting it later
@@ -0,0 +1,252 @@
+use crate::{AmdRaplRegisters, IntelRaplRegisters, RaplMeasurement};
+use std::sync::{
+    atomic::{fence, AtomicU32, AtomicU64, AtomicU8, Ordering},
+    Arc,
+};
+
+// Vendor tags stored in a slot, used to rebuild the RaplMeasurement variant
+const VENDOR_INTEL: u8 = 0;
+const VENDOR_AMD: u8 = 1;
+
+/// A timestamped RAPL sample together with the number of package counter overflows seen so far.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub timestamp: u128,
+    pub measurement: RaplMeasurement,
+    pub pkg_overflow: u32,
+}
+
+// Every field is atomic so a reader racing with the writer never reads torn memory.
+// The sequence number works as a seqlock: it is odd while the slot is written and
+// 2 * generation afterwards, which lets readers detect slots that have been overwritten.
+#[derive(Default)]
+struct Slot {
+    sequence: AtomicU64,
+    vendor: AtomicU8,
+    registers: [AtomicU64; 4],
+    pkg_overflow: AtomicU32,
+}
+
+struct Inner {
+    slots: Box<[Slot]>,
+    // Timestamps are kept apart from the slots so the binary search stays cache friendly
+    timestamps: Box<[AtomicU64]>,
+    // Total number of samples written, the newest sample is at head - 1
+    head: AtomicU64,
+}
+
+/// Creates a fixed-capacity, single-producer sample ring buffer.
+///
+/// The writer overwrites the oldest samples once the buffer is full, so memory use is constant
+/// no matter how far behind the readers are.
+pub fn sample_ring_buffer(capacity: usize) -> (SampleWriter, SampleReader) {
+    assert!(
+        capacity > 1,
+        "sample ring buffer needs room for at least two samples"
+    );
+
+    let inner = Arc::new(Inner {
+        slots: (0..capacity).map(|_| Slot::default()).collect(),
+        timestamps: (0..capacity).map(|_| AtomicU64::new(0)).collect(),
+        head: AtomicU64::new(0),
+    });
+
+    (
+        SampleWriter {
+            inner: inner.clone(),
+            pkg_overflow: 0,
+            last_pkg: 0,
+        },
+        SampleReader { inner },
+    )
+}
+
+/// The single producer of a sample ring buffer. It also keeps track of package counter overflows.
+pub struct SampleWriter {
+    inner: Arc<Inner>,
+    pkg_overflow: u32,
+    last_pkg: u64,
+}
+
+impl SampleWriter {
+    /// Append a measurement taken at the given timestamp (nanoseconds since the UNIX epoch).
+    pub fn push(&mut self, timestamp: u128, measurement: &RaplMeasurement) {
+        // finding overflows, by matching cpu type and checking pkg
+        let (vendor, registers, pkg) = match measurement {
+            RaplMeasurement::Intel(intel) => (
+                VENDOR_INTEL,
+                [intel.pp0, intel.pp1, intel.pkg, intel.dram],
+                intel.pkg,
+            ),
+            RaplMeasurement::AMD(amd) => (VENDOR_AMD, [amd.core, amd.pkg, 0, 0], amd.pkg),
+        };
+        if self.last_pkg > pkg {
+            self.pkg_overflow += 1;
+        }
+        self.last_pkg = pkg;
+
+        let inner = &*self.inner;
+        let index = inner.head.load(Ordering::Relaxed);
+        let position = (index % inner.slots.len() as u64) as usize;
+        let slot = &inner.slots[position];
+
+        // Mark the slot as being written
+        let sequence = slot.sequence.load(Ordering::Relaxed);
+        slot.sequence.store(sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        inner.timestamps[position].store(timestamp as u64, Ordering::Relaxed);
+        slot.vendor.store(vendor, Ordering::Relaxed);
+        for (register, value) in slot.registers.iter().zip(registers) {
+            register.store(value, Ordering::Relaxed);
+        }
+        slot.pkg_overflow
+            .store(self.pkg_overflow, Ordering::Relaxed);
+
+        // Publish the slot and then the new head
+        slot.sequence.store(sequence + 2, Ordering::Release);
+        inner.head.store(index + 1, Ordering::Release);
+    }
+}
+
+/// A reader of a sample ring buffer. Readers never block the writer.
+#[derive(Clone)]
+pub struct SampleReader {
+    inner: Arc<Inner>,
+}
+
+impl SampleReader {
+    pub fn capacity(&self) -> usize {
+        self.inner.slots.len()
+    }
+
+    /// Number of samples currently retained.
+    pub fn len(&self) -> usize {
+        let head = self.inner.head.load(Ordering::Acquire);
+        (head - self.oldest_index(head)) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The most recently written sample.
+    pub fn latest(&self) -> Option<Sample> {
+        let head = self.inner.head.load(Ordering::Acquire);
+        head.checked_sub(1).and_then(|index| self.read(index))
+    }
+
+    /// Find the newest sample taken at or before the timestamp, using a binary search.
+    pub fn find(&self, timestamp: u128) -> Option<Sample> {
+        self.find_index(timestamp)
+            .and_then(|index| self.read(index))
+            .filter(|sample| sample.timestamp <= timestamp)
+    }
+
+    /// Find the newest sample taken at or before the timestamp and the sample following it, if any.
+    pub fn find_with_next(&self, timestamp: u128) -> Option<(Sample, Option<Sample>)> {
+        let index = self.find_index(timestamp)?;
+        let sample = self
+            .read(index)
+            .filter(|sample| sample.timestamp <= timestamp)?;
+        Some((sample, self.read(index + 1)))
+    }
+
+    /// All retained samples taken within the inclusive timestamp range, oldest first.
+    pub fn range(&self, start: u128, end: u128) -> Vec<Sample> {
+        let head = self.inner.head.load(Ordering::Acquire);
+        let first = match self.find_index(start) {
+            Some(index) => index,
+            None => self.oldest_index(head),
+        };
+
+        (first..head)
+            .filter_map(|index| self.read(index))
+            .skip_while(|sample| sample.timestamp < start)
+            .take_while(|sample| sample.timestamp <= end)
+            .collect()
+    }
+
+    // Oldest logical index that is still likely to be intact. One slot is kept as
+    // margin as the writer may be overwriting the oldest sample right now.
+    fn oldest_index(&self, head: u64) -> u64 {
+        head.saturating_sub(self.capacity() as u64 - 1)
+    }
+
+    fn find_index(&self, timestamp: u128) -> Option<u64> {
+        let head = self.inner.head.load(Ordering::Acquire);
+
+        // Binary search for the first sample newer than the timestamp
+        let mut low = self.oldest_index(head);
+        let mut high = head;
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let middle_timestamp = self.read_timestamp(middle);
+            if middle_timestamp <= timestamp {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        low.checked_sub(1)
+            .filter(|&index| index >= self.oldest_index(head))
+    }
+
+    // Only read the timestamp of a sample, which is all the binary search needs. This skips
+    // the sequence check, the sample found by the search is validated when it is read.
+    fn read_timestamp(&self, index: u64) -> u128 {
+        let position = (index % self.capacity() as u64) as usize;
+        self.inner.timestamps[position].load(Ordering::Relaxed) as u128
+    }
+
+    // Read the sample with the given logical index, if it has not been overwritten
+    fn read(&self, index: u64) -> Option<Sample> {
+        let capacity = self.capacity() as u64;
+        let position = (index % capacity) as usize;
+        let slot = &self.inner.slots[position];
+        let expected_sequence = 2 * (index / capacity + 1);
+
+        // Anything but the expected sequence means the slot is not written yet or has been overwritten
+        let sequence = slot.sequence.load(Ordering::Acquire);
+        if sequence != expected_sequence {
+            return None;
+        }
+
+        let timestamp = self.inner.timestamps[position].load(Ordering::Relaxed) as u128;
+        let vendor = slot.vendor.load(Ordering::Relaxed);
+        let registers = [
+            slot.registers[0].load(Ordering::Relaxed),
+            slot.registers[1].load(Ordering::Relaxed),
+            slot.registers[2].load(Ordering::Relaxed),
+            slot.registers[3].load(Ordering::Relaxed),
+        ];
+        let pkg_overflow = slot.pkg_overflow.load(Ordering::Relaxed);
+
+        fence(Ordering::Acquire);
+        if slot.sequence.load(Ordering::Relaxed) != sequence {
+            // The writer lapped us while reading
+            return None;
+        }
+
+        let measurement = if vendor == VENDOR_INTEL {
+            RaplMeasurement::Intel(IntelRaplRegisters {
+                pp0: registers[0],
+                pp1: registers[1],
+                pkg: registers[2],
+                dram: registers[3],
+            })
+        } else {
+            RaplMeasurement::AMD(AmdRaplRegisters {
+                core: registers[0],
+                pkg: registers[1],
+            })
+        };
+
+        Some(Sample {
+            timestamp,
+            measurement,
+            pkg_overflow,
+        })
+    }
+}
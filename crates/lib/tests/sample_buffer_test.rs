@@ -0,0 +1,70 @@
+use thor_lib::{
+    sample_buffer::sample_ring_buffer, AmdRaplRegisters, IntelRaplRegisters, RaplMeasurement,
+};
+
+fn intel_measurement(pkg: u64) -> RaplMeasurement {
+    RaplMeasurement::Intel(IntelRaplRegisters {
+        pp0: 1,
+        pp1: 2,
+        pkg,
+        dram: 3,
+    })
+}
+
+#[test]
+fn test_find_newest_sample_before_timestamp() {
+    let (mut writer, reader) = sample_ring_buffer(8);
+    for i in 1..=5 {
+        writer.push(i * 100, &intel_measurement(i as u64));
+    }
+
+    assert!(reader.find(99).is_none());
+    assert_eq!(reader.find(100).unwrap().timestamp, 100);
+    assert_eq!(reader.find(250).unwrap().timestamp, 200);
+    assert_eq!(reader.find(10_000).unwrap().timestamp, 500);
+    assert_eq!(reader.find(300).unwrap().measurement, intel_measurement(3));
+}
+
+#[test]
+fn test_old_samples_are_overwritten() {
+    let (mut writer, reader) = sample_ring_buffer(4);
+    for i in 1..=10 {
+        writer.push(i * 100, &intel_measurement(i as u64));
+    }
+
+    // The oldest slot is kept as margin for the writer
+    assert_eq!(reader.len(), 3);
+    assert!(reader.find(750).is_none());
+    assert_eq!(reader.find(850).unwrap().timestamp, 800);
+    assert_eq!(reader.latest().unwrap().timestamp, 1000);
+
+    let (sample, next) = reader.find_with_next(850).unwrap();
+    assert_eq!(sample.timestamp, 800);
+    assert_eq!(next.unwrap().timestamp, 900);
+
+    let timestamps: Vec<u128> = reader
+        .range(750, 950)
+        .iter()
+        .map(|sample| sample.timestamp)
+        .collect();
+    assert_eq!(timestamps, vec![800, 900]);
+}
+
+#[test]
+fn test_pkg_overflow_is_counted() {
+    let (mut writer, reader) = sample_ring_buffer(8);
+    writer.push(100, &intel_measurement(u32::MAX as u64 - 1));
+    writer.push(200, &intel_measurement(10));
+    writer.push(
+        300,
+        &RaplMeasurement::AMD(AmdRaplRegisters { core: 7, pkg: 5 }),
+    );
+
+    assert_eq!(reader.find(100).unwrap().pkg_overflow, 0);
+    assert_eq!(reader.find(200).unwrap().pkg_overflow, 1);
+    assert_eq!(reader.find(300).unwrap().pkg_overflow, 2);
+    assert_eq!(
+        reader.find(300).unwrap().measurement,
+        RaplMeasurement::AMD(AmdRaplRegisters { core: 7, pkg: 5 })
+    );
+}
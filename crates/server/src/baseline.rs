@@ -0,0 +1,62 @@
+use crate::{component_def::Measurement, timeline::window_watts};
+use thor_lib::RaplMeasurementJoules;
+use thor_shared::Baseline;
+
+// The baseline period is split into windows of this length to estimate the variance of the power
+const BASELINE_WINDOW_NANOS: u128 = 100_000_000;
+
+/// Measure the idle power between two timestamps, None if the samples between them are no longer retained.
+pub fn measure_baseline<M: Measurement<(RaplMeasurementJoules, u32)>>(
+    measurement: &mut M,
+    start_timestamp: u128,
+    stop_timestamp: u128,
+    counter_wrap_joules: f64,
+) -> Option<Baseline> {
+    let window_count = ((stop_timestamp - start_timestamp) / BASELINE_WINDOW_NANOS).max(1);
+    let window_nanos = (stop_timestamp - start_timestamp) / window_count;
+
+    let timestamps: Vec<u128> = (0..=window_count)
+        .map(|window| start_timestamp + window * window_nanos)
+        .collect();
+    let window_watts = window_watts(measurement, &timestamps, counter_wrap_joules)?;
+
+    let count = window_watts.len() as f64;
+    let watts = sum(&window_watts).map(|watts| watts / count);
+    let squared_deviations: Vec<RaplMeasurementJoules> = window_watts
+        .iter()
+        .map(|window| window.zip_with(&watts, |window, mean| (window - mean).powi(2)))
+        .collect();
+    let variance = sum(&squared_deviations).map(|squared| {
+        if window_watts.len() > 1 {
+            squared / (count - 1.0)
+        } else {
+            0.0
+        }
+    });
+
+    Some(Baseline {
+        start_timestamp,
+        stop_timestamp: timestamps[timestamps.len() - 1],
+        watts,
+        variance,
+    })
+}
+
+/// Energy above the baseline for a region of the given duration. This can be negative for
+/// regions that used less than the idle power.
+pub fn energy_above_baseline(
+    energy: &RaplMeasurementJoules,
+    duration_nanos: u128,
+    baseline: &Baseline,
+) -> RaplMeasurementJoules {
+    let seconds = duration_nanos as f64 / 1_000_000_000.0;
+    energy.zip_with(&baseline.watts, |joules, watts| joules - watts * seconds)
+}
+
+fn sum(measurements: &[RaplMeasurementJoules]) -> RaplMeasurementJoules {
+    measurements[1..]
+        .iter()
+        .fold(measurements[0].clone(), |sum, measurement| {
+            sum.zip_with(measurement, |a, b| a + b)
+        })
+}
@@ -64,11 +64,11 @@ impl Build for GitBuild {
 fn try_command(command: &mut Command, error_message: &str) -> Result<(), io::Error> {
     let output = command.output().expect(error_message);
 
-    if output.stderr.len() > 0 {
+    if !output.stderr.is_empty() {
         println!("stderr: {}", String::from_utf8_lossy(&output.stderr));
     }
 
-    if output.stdout.len() > 0 {
+    if !output.stdout.is_empty() {
         println!("stdout: {}", String::from_utf8_lossy(&output.stdout));
     }
     Ok(())
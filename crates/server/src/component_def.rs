@@ -1,12 +1,20 @@
 use anyhow::Result;
 use std::io;
+use thor_shared::EnergyQueryResult;
 
 pub trait Measurement<T> {
-    // T is the type of measurement
-    fn get_measurement(&mut self, timestamp: u128) -> T;
+    // T is the type of measurement, None if the timestamp is outside of the retained measurements
+    fn try_get_measurement(&mut self, timestamp: u128) -> Option<T>;
 
-    // for matching multiple measurements at a time
-    fn get_multiple_measurements(&mut self, timestamps: &Vec<u128>) -> Vec<T>;
+    // time in nanoseconds between the samples around the timestamp, None if there is no sample before it
+    fn get_sample_spacing(&mut self, timestamp: u128) -> Option<u128>;
+
+    // energy used between two past timestamps, an error if they are reversed or outside of the retained measurements
+    fn query_energy(
+        &mut self,
+        start_timestamp: u128,
+        stop_timestamp: u128,
+    ) -> Result<EnergyQueryResult, String>;
 }
 
 pub trait Build {
@@ -1,4 +1,5 @@
 use serde::Deserialize;
+use thor_lib::cpu_vendor;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
@@ -27,4 +28,71 @@ pub struct ThorConfig {
     pub sampling_interval_micros: u64,
     pub max_sample_age_millis: u64,
     pub server_ip: String,
+    /// How long the idle power is measured for
+    #[serde(default = "default_baseline_millis")]
+    pub baseline_millis: u64,
+    /// Measure the idle power before each job, it can otherwise be requested by clients
+    #[serde(default)]
+    pub baseline_before_jobs: bool,
+    /// Regions whose package energy uncertainty is above this share of their package energy are flagged as unreliable
+    #[serde(default = "default_reliability_threshold")]
+    pub reliability_threshold: f64,
+    /// Report region energy corrected for the instrumentation overhead measured by a calibration
+    #[serde(default)]
+    pub correct_overhead: bool,
+    /// Length of the steps of the power timeline sent to clients that ask for it
+    #[serde(default = "default_timeline_resolution_micros")]
+    pub timeline_resolution_micros: u64,
+    /// Unix domain socket processes under test can connect to, in addition to server_ip
+    #[serde(default)]
+    pub unix_socket_path: Option<String>,
+    /// Permissions of the Unix domain socket
+    #[serde(default = "default_unix_socket_mode")]
+    pub unix_socket_mode: u32,
+    /// Accept the shared memory rings of processes under test over the Unix socket
+    #[serde(default)]
+    pub shm_rings: bool,
+    /// How often the shared memory rings are read
+    #[serde(default = "default_shm_poll_interval_micros")]
+    pub shm_poll_interval_micros: u64,
+}
+
+impl Config {
+    /// Names of the RAPL domains enabled for the CPU vendor the server is built for.
+    pub fn enabled_domains(&self) -> Vec<String> {
+        let domains = match cpu_vendor() {
+            "AMD" => vec![("core", self.amd.core), ("pkg", self.amd.pkg)],
+            _ => vec![
+                ("pp0", self.intel.pp0),
+                ("pp1", self.intel.pp1),
+                ("pkg", self.intel.pkg),
+                ("dram", self.intel.dram),
+            ],
+        };
+        domains
+            .into_iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(domain, _)| domain.to_string())
+            .collect()
+    }
+}
+
+fn default_baseline_millis() -> u64 {
+    1000
+}
+
+fn default_reliability_threshold() -> f64 {
+    0.1
+}
+
+fn default_timeline_resolution_micros() -> u64 {
+    10_000
+}
+
+fn default_unix_socket_mode() -> u32 {
+    0o660
+}
+
+fn default_shm_poll_interval_micros() -> u64 {
+    1000
 }
@@ -0,0 +1,144 @@
+use thor_shared::CpuTime;
+
+/// Read the CPU time used so far by a thread of a process and by the whole machine.
+#[cfg(target_os = "linux")]
+pub fn read_cpu_time(process_id: u32, thread_id: usize) -> Option<CpuTime> {
+    use std::fs;
+
+    let thread_stat =
+        fs::read_to_string(format!("/proc/{}/task/{}/stat", process_id, thread_id)).ok()?;
+    let machine_stat = fs::read_to_string("/proc/stat").ok()?;
+
+    Some(CpuTime {
+        thread_ticks: parse_thread_ticks(&thread_stat)?,
+        machine_ticks: parse_machine_busy_ticks(&machine_stat)?,
+    })
+}
+
+// CPU time is only read from procfs for now
+#[cfg(not(target_os = "linux"))]
+pub fn read_cpu_time(_process_id: u32, _thread_id: usize) -> Option<CpuTime> {
+    None
+}
+
+/// Read the number of times a thread has moved to another CPU, which needs a kernel with scheduler debugging.
+#[cfg(target_os = "linux")]
+pub fn read_cpu_migrations(process_id: u32, thread_id: usize) -> Option<u64> {
+    let sched =
+        std::fs::read_to_string(format!("/proc/{}/task/{}/sched", process_id, thread_id)).ok()?;
+    parse_cpu_migrations(&sched)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn read_cpu_migrations(_process_id: u32, _thread_id: usize) -> Option<u64> {
+    None
+}
+
+/// The share of the machine's CPU time used by the thread between two readings.
+pub fn cpu_time_share(start: &CpuTime, stop: &CpuTime) -> f64 {
+    let thread_ticks = stop.thread_ticks.saturating_sub(start.thread_ticks);
+    let machine_ticks = stop.machine_ticks.saturating_sub(start.machine_ticks);
+
+    if machine_ticks == 0 {
+        0.0
+    } else {
+        (thread_ticks as f64 / machine_ticks as f64).min(1.0)
+    }
+}
+
+// utime + stime from /proc/<pid>/task/<tid>/stat. The command name may contain spaces,
+// so the fields are counted from the closing parenthesis after it.
+#[cfg(target_os = "linux")]
+fn parse_thread_ticks(stat: &str) -> Option<u64> {
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+    // utime and stime are the 14th and 15th fields, the 12th and 13th after the command name
+    let utime: u64 = fields.nth(11)?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(utime + stime)
+}
+
+// The se.nr_migrations line of /proc/<pid>/task/<tid>/sched
+#[cfg(target_os = "linux")]
+fn parse_cpu_migrations(sched: &str) -> Option<u64> {
+    sched.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        (name.trim() == "se.nr_migrations")
+            .then(|| value.trim().parse().ok())
+            .flatten()
+    })
+}
+
+// Non-idle time of all CPUs from the first line of /proc/stat
+#[cfg(target_os = "linux")]
+fn parse_machine_busy_ticks(stat: &str) -> Option<u64> {
+    let ticks: Vec<u64> = stat
+        .lines()
+        .next()?
+        .strip_prefix("cpu ")?
+        .split_whitespace()
+        .map(|field| field.parse().ok())
+        .collect::<Option<_>>()?;
+
+    // user, nice, system, irq, softirq and steal, guest time is already part of user
+    Some(
+        [0, 1, 2, 5, 6, 7]
+            .iter()
+            .filter_map(|&index| ticks.get(index))
+            .sum(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_time_share() {
+        let start = CpuTime {
+            thread_ticks: 10,
+            machine_ticks: 100,
+        };
+        let stop = CpuTime {
+            thread_ticks: 15,
+            machine_ticks: 120,
+        };
+        assert_eq!(cpu_time_share(&start, &stop), 0.25);
+        assert_eq!(cpu_time_share(&start, &start), 0.0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_thread_ticks() {
+        // The command name can contain spaces and parentheses
+        let stat =
+            "1234 (my (odd) cmd) S 1 1234 1234 0 -1 4194560 100 0 0 0 42 8 0 0 20 0 1 0 100 0 0";
+        assert_eq!(parse_thread_ticks(stat), Some(50));
+        assert_eq!(parse_thread_ticks("1234 (cmd) S 1 2"), None);
+        assert_eq!(parse_thread_ticks("no command name"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_cpu_migrations() {
+        let sched = "cat (1234, #threads: 1)\n\
+            -------------------------------------------------------------------\n\
+            se.exec_start                                :       1234567.891011\n\
+            se.nr_migrations                             :                    3\n\
+            nr_switches                                  :                   10\n";
+        assert_eq!(parse_cpu_migrations(sched), Some(3));
+        assert_eq!(parse_cpu_migrations("nr_switches : 10\n"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_machine_busy_ticks() {
+        let stat = "cpu  100 20 30 1000 50 6 7 8 9 10\ncpu0 50 10 15 500 25 3 3 4 4 5\n";
+        // Idle and iowait are left out, as are the guest times counted in user
+        assert_eq!(
+            parse_machine_busy_ticks(stat),
+            Some(100 + 20 + 30 + 6 + 7 + 8)
+        );
+        assert_eq!(parse_machine_busy_ticks("cpu0 1 2 3\n"), None);
+        assert_eq!(parse_machine_busy_ticks("cpu  1 x 3\n"), None);
+    }
+}
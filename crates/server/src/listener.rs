@@ -1,26 +1,148 @@
 use crate::{
+    baseline::measure_baseline,
     build::GitBuild,
     component_def::{Build, Listener, Measurement},
+    cpu_time::{read_cpu_migrations, read_cpu_time},
+    measurement::get_timestamp,
+    regions::{region_energy, RegionTracker},
+    sessions::SessionTracker,
+    timeline::TimelineCursor,
+    uncertainty::{marker_uncertainty, POWER_WINDOW_NANOS},
+    validation::ConnectionValidator,
 };
 use anyhow::Result;
-use crossbeam::queue::SegQueue;
+use crossbeam::{
+    channel::{self, Sender},
+    queue::SegQueue,
+};
 use std::{
     collections::VecDeque,
     io::Write,
     net::Shutdown,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
     thread,
-    time::{Duration, SystemTime},
+    time::Duration,
+};
+use thor_analysis::regions::RegionAggregator;
+use thor_lib::{
+    counter_wrap_joules, cpu_vendor, energy_unit_joules, read_core_energy, RaplMeasurementJoules,
+};
+use thor_shared::{
+    protocol::{self, ClientEncoding, RegionTable, TextMarkerError},
+    ClientMessage, ClientPacket, ConnectionType, CpuTime, JobSummary, PowerTimeline,
+    ProcessSession, ProcessUnderTestMessage, ProcessUnderTestPacket,
+    ProcessUnderTestPacketOperation, ServerHello, TimelineMarker, ValidationReport,
 };
-use thor_lib::RaplMeasurementJoules;
-use thor_shared::{ClientPacket, ConnectionType, ProcessUnderTestPacket};
-use tokio::{io::AsyncReadExt, net::TcpListener};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+
+static EVENT_QUEUE: SegQueue<Event> = SegQueue::new();
+
+// Events of processes under test, passed on in order by a thread that reads the CPU time and
+// core energy of the markers, as the reads block
+static PROCESS_UNDER_TEST_EVENTS: OnceLock<Sender<ProcessUnderTestEvent>> = OnceLock::new();
+
+// Rings accepted over the Unix socket, passed on to the thread polling them
+#[cfg(target_os = "linux")]
+static SHM_RINGS: OnceLock<Sender<ShmRingConnection>> = OnceLock::new();
+
+#[cfg(target_os = "linux")]
+struct ShmRingConnection {
+    ring: thor_shared::shm_ring::ShmRing,
+    // Closed by the process when it exits
+    socket: std::os::unix::net::UnixStream,
+}
+
+// Numbers the binary connections of processes under test, whose markers carry sequence numbers
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(0);
 
-static PROCESS_UNDER_TEST_PACKET_QUEUE: SegQueue<ProcessUnderTestPacket> = SegQueue::new();
+// Processes that lost their connection, whose open regions are stopped unless they connect again
+// within the grace period
+static DISCONNECTED: Mutex<Vec<DisconnectedProcess>> = Mutex::new(Vec::new());
+
+// The libraries reconnect on their next marker, retrying every second
+const RECONNECT_GRACE: Duration = Duration::from_secs(2);
+
+struct DisconnectedProcess {
+    // The session and process id of the process
+    sender: (String, u32),
+    connection: u64,
+    validator: ConnectionValidator,
+}
+
+enum ProcessUnderTestEvent {
+    Marker(ProcessUnderTestPacket, Option<u64>),
+    Ready(Event),
+}
+
+enum Event {
+    Packet(ReceivedPacket),
+    Report(ValidationReport),
+    // A process under test connected in a session
+    Session(u64, ProcessSession),
+    Disconnected(u64),
+    // Idle power was measured between the timestamps
+    Baseline {
+        start_timestamp: u128,
+        stop_timestamp: u128,
+    },
+    // A job is about to be built and run
+    JobStarted,
+    JobFinished {
+        repo: String,
+    },
+    // A client asked for the energy between two timestamps, the answer is sent over the stream
+    EnergyQuery {
+        start_timestamp: u128,
+        stop_timestamp: u128,
+        stream: std::net::TcpStream,
+        encoding: ClientEncoding,
+    },
+}
+
+struct ReceivedPacket {
+    packet: ProcessUnderTestPacket,
+    // Made up by the server when the process disconnected with open regions
+    synthetic: bool,
+    // The binary connection the packet was received on
+    connection: Option<u64>,
+    cpu_time: Option<CpuTime>,
+    core_energy: Option<f64>,
+    cpu_migrations: Option<u64>,
+}
 
 pub struct ListenerImplem {
     pub ip: String,
     pub client_packet_queue_cycle: u64,
+    pub baseline_millis: u64,
+    pub baseline_before_jobs: bool,
+    pub reliability_threshold: f64,
+    pub correct_overhead: bool,
+    pub timeline_resolution_micros: u64,
+    pub enabled_domains: Vec<String>,
+    /// How long samples are kept, in milliseconds
+    pub max_sample_age: u64,
+    pub unix_socket: Option<UnixSocketSettings>,
+    pub shm_rings: Option<ShmRingSettings>,
+}
+
+/// Unix domain socket that processes under test can connect to besides TCP.
+#[derive(Clone)]
+pub struct UnixSocketSettings {
+    pub path: String,
+    /// Permissions of the socket file, e.g. 0o660 to only allow the owner and group
+    pub mode: u32,
+}
+
+/// Shared memory rings processes under test hand to the server over the Unix socket.
+#[derive(Clone)]
+pub struct ShmRingSettings {
+    pub poll_interval_micros: u64,
 }
 
 // Needle for the end of a string (used for repoes)
@@ -29,8 +151,60 @@ const NEEDLE: &[u8] = "#".as_bytes();
 // max size for repo url
 const MAX_REPO_SIZE: usize = 1024;
 
-// Delimiter for the send measurements to the clients
-const MEASUREMENTS_DELIMITER: &[u8] = "end".as_bytes();
+// Prefix of the options a client can give after the repo
+const CLIENT_OPTION_PREFIX: &str = "--";
+
+// Prefix of the option choosing the encoding of the messages
+const ENCODING_OPTION_PREFIX: &str = "encoding=";
+
+// Prefix of a request for the energy between two timestamps instead of a repo
+const ENERGY_QUERY_PREFIX: &str = "query ";
+
+type ClientConnections = Arc<Mutex<Vec<ClientConnection>>>;
+
+#[derive(Clone, Copy)]
+struct BaselineSettings {
+    millis: u64,
+    before_jobs: bool,
+}
+
+struct ClientConnection {
+    stream: std::net::TcpStream,
+    options: ClientOptions,
+}
+
+#[derive(Debug, Default)]
+struct ClientOptions {
+    encoding: ClientEncoding,
+    // Send the raw Start/Stop packets in addition to the region results
+    raw_packets: bool,
+    // Measure the idle power when connecting
+    baseline: bool,
+    // Send the statistics of the regions aggregated by id whenever they change
+    statistics: bool,
+    // Send the power timeline with the markers overlaid
+    timeline: bool,
+}
+
+impl ClientOptions {
+    fn wants(&self, client_message: &ClientMessage) -> bool {
+        match client_message {
+            ClientMessage::Packet(_) => self.raw_packets,
+            ClientMessage::Statistics(_) => self.statistics,
+            ClientMessage::PowerTimeline(_) => self.timeline,
+            // Only sent to the client that asked
+            ClientMessage::EnergyQuery(_) => false,
+            ClientMessage::Hello(_)
+            | ClientMessage::Region(_)
+            | ClientMessage::RegionError(_)
+            | ClientMessage::ValidationReport(_)
+            | ClientMessage::Baseline(_)
+            | ClientMessage::JobSummary(_)
+            | ClientMessage::InstrumentationOverhead(_)
+            | ClientMessage::Mark(_) => true,
+        }
+    }
+}
 
 impl Listener<(RaplMeasurementJoules, u32)> for ListenerImplem {
     fn start_listening<M: Measurement<(RaplMeasurementJoules, u32)>>(
@@ -44,16 +218,42 @@ impl Listener<(RaplMeasurementJoules, u32)> for ListenerImplem {
         let client_tcpstreams_clone = client_tcpstreams.clone();
 
         let ip = self.ip.clone();
+        let baseline_settings = BaselineSettings {
+            millis: self.baseline_millis,
+            before_jobs: self.baseline_before_jobs,
+        };
+        let enabled_domains = Arc::new(self.enabled_domains.clone());
+        let unix_socket = self.unix_socket.clone();
+        // Markers are measured up to one queue cycle after they are received
+        let marker_window =
+            (self.max_sample_age - self.client_packet_queue_cycle) as u128 * 1_000_000;
+
+        if let Some(shm_rings) = self.shm_rings.clone() {
+            if self.unix_socket.is_none() {
+                println!("Shared memory rings need the Unix socket, set unix_socket_path");
+            }
+            poll_shm_rings(shm_rings);
+        }
 
         // Creating thread for listening
         thread::spawn(move || {
-            let fut = listen(ip, client_tcpstreams_clone);
+            let fut = listen(
+                ip,
+                client_tcpstreams_clone,
+                baseline_settings,
+                enabled_domains,
+                unix_socket,
+                marker_window,
+            );
             tokio::runtime::Runtime::new().unwrap().block_on(fut);
         });
 
         send_packet_to_clients(
             client_tcpstreams,
             self.client_packet_queue_cycle,
+            self.reliability_threshold,
+            self.correct_overhead,
+            self.timeline_resolution_micros,
             measurement,
         );
 
@@ -61,7 +261,18 @@ impl Listener<(RaplMeasurementJoules, u32)> for ListenerImplem {
     }
 }
 
-async fn listen(server_ip: String, client_tcpstreams: Arc<Mutex<Vec<std::net::TcpStream>>>) {
+async fn listen(
+    server_ip: String,
+    client_tcpstreams: ClientConnections,
+    baseline_settings: BaselineSettings,
+    enabled_domains: Arc<Vec<String>>,
+    unix_socket: Option<UnixSocketSettings>,
+    marker_window: u128,
+) {
+    if let Some(unix_socket) = unix_socket {
+        listen_unix(unix_socket, marker_window);
+    }
+
     // Create a TCP listener
     println!("Listening on: {}", server_ip);
     let tcp_listener = TcpListener::bind(&server_ip).await.unwrap();
@@ -71,51 +282,557 @@ async fn listen(server_ip: String, client_tcpstreams: Arc<Mutex<Vec<std::net::Tc
         let (mut socket, _) = tcp_listener.accept().await.unwrap();
 
         // Read the connection type and handle it
-        let connection_type = socket.read_u8().await.unwrap();
+        let Ok(connection_type) = socket.read_u8().await else {
+            continue;
+        };
         if connection_type == ConnectionType::ProcessUnderTest as u8 {
-            handle_process_under_test_connection(socket);
+            handle_process_under_test_connection(socket, marker_window);
+        } else if connection_type == ConnectionType::Text as u8 {
+            handle_text_connection(socket, marker_window);
         } else {
-            handle_client_connection(client_tcpstreams.clone(), socket).await;
+            handle_client_connection(
+                client_tcpstreams.clone(),
+                socket,
+                baseline_settings,
+                &enabled_domains,
+            )
+            .await;
         }
     }
 }
 
-fn handle_process_under_test_connection(mut socket: tokio::net::TcpStream) {
-    tokio::spawn(async move {
-        let mut client_buffer = vec![0; u8::MAX as usize];
+// Only processes under test connect over the Unix socket
+#[cfg(unix)]
+fn listen_unix(unix_socket: UnixSocketSettings, marker_window: u128) {
+    use std::{
+        fs,
+        os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt},
+        path::Path,
+    };
+    use tokio::net::UnixListener;
+
+    let path = Path::new(&unix_socket.path);
+    if let Some(directory) = path.parent() {
+        if let Err(err) = fs::create_dir_all(directory) {
+            println!(
+                "Could not create the directory of {}, Error: {:?}",
+                unix_socket.path, err
+            );
+            return;
+        }
+    }
+
+    // A socket left by a previous run would make binding fail. Files of other users are left alone
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        // Safety: geteuid has no preconditions and can not fail
+        if !metadata.file_type().is_socket() || metadata.uid() != unsafe { libc::geteuid() } {
+            println!(
+                "Not listening on: {}, it is not a socket of the server",
+                unix_socket.path
+            );
+            return;
+        }
+        let _ = fs::remove_file(path);
+    }
 
+    println!("Listening on: {}", unix_socket.path);
+    let unix_listener = match UnixListener::bind(path) {
+        Ok(unix_listener) => unix_listener,
+        Err(err) => {
+            println!(
+                "Could not listen on: {}, Error: {:?}",
+                unix_socket.path, err
+            );
+            return;
+        }
+    };
+    fs::set_permissions(
+        &unix_socket.path,
+        fs::Permissions::from_mode(unix_socket.mode),
+    )
+    .unwrap();
+
+    tokio::spawn(async move {
         loop {
-            // Read the length of the packet
-            let process_under_test_packet_length = match socket.read_u8().await {
+            let (mut socket, _) = match unix_listener.accept().await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    println!("Failed to accept Unix socket connection: {:?}", err);
+                    continue;
+                }
+            };
+
+            let Ok(connection_type) = socket.read_u8().await else {
+                continue;
+            };
+            if connection_type == ConnectionType::ProcessUnderTest as u8 {
+                handle_process_under_test_connection(socket, marker_window);
+            } else if connection_type == ConnectionType::Text as u8 {
+                handle_text_connection(socket, marker_window);
+            } else if connection_type == ConnectionType::ShmRing as u8 {
+                handle_shm_ring_connection(socket);
+            } else {
+                println!("Only processes under test can connect over the Unix socket");
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn listen_unix(unix_socket: UnixSocketSettings, _marker_window: u128) {
+    println!(
+        "Unix sockets are not supported on this platform, not listening on: {}",
+        unix_socket.path
+    );
+}
+
+// The process sends its protocol version and gets the server's back, which it checks as well
+async fn exchange_handshake<S>(socket: &mut S) -> bool
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut handshake = [0; 2];
+    if socket.read_exact(&mut handshake).await.is_err() {
+        return false;
+    }
+    if socket
+        .write_all(&protocol::encode_handshake())
+        .await
+        .is_err()
+    {
+        return false;
+    }
+    if let Err(err) = protocol::check_handshake(handshake) {
+        println!("Closing process under test connection: {}", err);
+        return false;
+    }
+    true
+}
+
+// Receives the ring of a process and passes it to the thread polling the rings. The connection
+// stays open until the process exits
+#[cfg(target_os = "linux")]
+fn handle_shm_ring_connection(mut socket: tokio::net::UnixStream) {
+    use std::io;
+    use thor_shared::shm_ring::{receive_fd, ShmRing};
+
+    tokio::spawn(async move {
+        if !exchange_handshake(&mut socket).await {
+            return;
+        }
+        let Some(rings) = SHM_RINGS.get() else {
+            println!("Shared memory rings are not enabled, refusing the ring of a process");
+            return;
+        };
+        let Some(process_id) = socket
+            .peer_cred()
+            .ok()
+            .and_then(|credentials| credentials.pid())
+        else {
+            return;
+        };
+        let Ok(socket) = socket.into_std() else {
+            return;
+        };
+
+        let received = tokio::task::spawn_blocking(move || -> io::Result<ShmRingConnection> {
+            socket.set_nonblocking(false)?;
+            socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+            let ring = ShmRing::from_fd(receive_fd(&socket)?)?;
+            if ring.process_id() != process_id as u32 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "ring of another process",
+                ));
+            }
+            (&socket).write_all(&[1])?;
+            // Polled for the process closing it
+            socket.set_nonblocking(true)?;
+            Ok(ShmRingConnection { ring, socket })
+        })
+        .await;
+
+        match received {
+            Ok(Ok(connection)) => {
+                let _ = rings.send(connection);
+            }
+            Ok(Err(err)) => println!(
+                "Refusing the shared memory ring of process {}: {}",
+                process_id, err
+            ),
+            Err(err) => println!("Failed to receive a shared memory ring: {:?}", err),
+        }
+    });
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn handle_shm_ring_connection(_socket: tokio::net::UnixStream) {
+    println!("Shared memory rings are not supported on this platform");
+}
+
+fn handle_process_under_test_connection<S>(mut socket: S, marker_window: u128)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let connection = NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed);
+        let mut sender = None;
+        let mut validator = ConnectionValidator::default();
+        let mut region_table = RegionTable::default();
+
+        if !exchange_handshake(&mut socket).await {
+            return;
+        }
+
+        // Read the length of the packet, stopping if the client has disconnected
+        let mut header = [0; protocol::FRAME_HEADER_LENGTH];
+        while socket.read_exact(&mut header).await.is_ok() {
+            let process_under_test_packet_length = match protocol::decode_frame_length(header) {
                 Ok(length) => length,
-                Err(_) => {
-                    // If the client has disconnected, break the loop
+                Err(err) => {
+                    println!("Closing process under test connection: {}", err);
                     break;
                 }
             };
 
             // Read the packet itself
-            if let Err(_) = socket
-                .read_exact(&mut client_buffer[0..process_under_test_packet_length as usize])
+            let mut client_buffer = vec![0; process_under_test_packet_length];
+            if socket.read_exact(&mut client_buffer).await.is_err() {
+                // If the client has disconnected, break the loop
+                break;
+            }
+
+            // Deserialize the packet, a malformed packet only closes this connection
+            let message = match protocol::decode_message(&client_buffer) {
+                Ok(message) => message,
+                Err(err) => {
+                    println!("Closing process under test connection: {}", err);
+                    break;
+                }
+            };
+
+            if let ProcessUnderTestMessage::Session(process_session) = &message {
+                push_process_under_test_event(ProcessUnderTestEvent::Ready(Event::Session(
+                    connection,
+                    process_session.clone(),
+                )));
+
+                // A process that connects again continues the regions it opened before
+                let key = (process_session.session.clone(), process_session.process_id);
+                if let Some(disconnected) =
+                    take_disconnected(|disconnected| disconnected.sender == key)
+                {
+                    validator = disconnected.validator;
+                }
+                sender = Some(key);
+            }
+
+            // The markers name their region by a handle registered earlier on the connection
+            let process_under_test_packet = match region_table.resolve(message) {
+                Ok(Some(process_under_test_packet)) => process_under_test_packet,
+                Ok(None) => continue,
+                Err(err) => {
+                    println!("Skipping process under test packet: {}", err);
+                    continue;
+                }
+            };
+
+            validator.handle_packet(&process_under_test_packet);
+            push_process_under_test_packet(process_under_test_packet, Some(connection));
+        }
+
+        push_process_under_test_event(ProcessUnderTestEvent::Ready(Event::Disconnected(
+            connection,
+        )));
+        let disconnected_timestamp = get_timestamp();
+        match sender {
+            // Stopped at the time of the disconnect, which must still be sampled once the grace period is over
+            Some(sender) if process_running(sender.1) => {
+                DISCONNECTED.lock().unwrap().push(DisconnectedProcess {
+                    sender,
+                    connection,
+                    validator,
+                });
+                let marker_window = Duration::from_nanos((marker_window / 2) as u64);
+                tokio::time::sleep(RECONNECT_GRACE.min(marker_window)).await;
+                if let Some(disconnected) =
+                    take_disconnected(|disconnected| disconnected.connection == connection)
+                {
+                    finish_process_under_test(disconnected.validator, disconnected_timestamp);
+                }
+            }
+            _ => finish_process_under_test(validator, disconnected_timestamp),
+        }
+    });
+}
+
+// Scripts send one marker per line, often over a connection per marker, so regions are paired
+// across connections and open regions are not stopped when a connection closes
+fn handle_text_connection<S>(socket: S, marker_window: u128)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let (reader, mut writer) = tokio::io::split(socket);
+        let mut reader = BufReader::new(reader);
+        let mut buffer = Vec::new();
+
+        loop {
+            // Lines are as long as frames at most, so a script that never ends its line can not fill the memory
+            buffer.clear();
+            let limit = protocol::MAX_FRAME_LENGTH as u64 + 1;
+            match (&mut reader)
+                .take(limit)
+                .read_until(b'\n', &mut buffer)
                 .await
             {
-                // If the client has disconnected, break the loop
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            if !buffer.ends_with(b"\n") && buffer.len() > protocol::MAX_FRAME_LENGTH {
+                let _ = writer
+                    .write_all(format!("error: {}\n", TextMarkerError::TooLong).as_bytes())
+                    .await;
                 break;
             }
+            let Ok(line) = std::str::from_utf8(&buffer) else {
+                break;
+            };
 
-            // Deserialize the packet using the buffer
-            let process_under_test_packet: ProcessUnderTestPacket =
-                bincode::deserialize(&client_buffer).unwrap();
+            // Stamped on receipt, unless the script gives its own timestamp
+            match parse_text_marker_in_window(line, get_timestamp(), marker_window) {
+                Ok(Some(process_under_test_packet)) => {
+                    push_process_under_test_packet(process_under_test_packet, None)
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    // The script may not read the answer, so failing to send it is ignored
+                    let _ = writer
+                        .write_all(format!("error: {}\n", err).as_bytes())
+                        .await;
+                }
+            }
+        }
+    });
+}
 
-            // Push the packet to the process under test packet queue
-            PROCESS_UNDER_TEST_PACKET_QUEUE.push(process_under_test_packet);
+// Scripts can give any timestamp, but only those of past samples the server still retains when the
+// marker is measured can be measured
+fn parse_text_marker_in_window(
+    line: &str,
+    received_timestamp: u128,
+    marker_window: u128,
+) -> Result<Option<ProcessUnderTestPacket>, TextMarkerError> {
+    let process_under_test_packet = protocol::parse_text_marker(line, received_timestamp)?;
+    if let Some(packet) = &process_under_test_packet {
+        if packet.timestamp > received_timestamp
+            || received_timestamp - packet.timestamp > marker_window
+        {
+            return Err(TextMarkerError::OutsideWindow(packet.timestamp));
         }
+    }
+    Ok(process_under_test_packet)
+}
+
+fn push_process_under_test_packet(
+    process_under_test_packet: ProcessUnderTestPacket,
+    connection: Option<u64>,
+) {
+    push_process_under_test_event(ProcessUnderTestEvent::Marker(
+        process_under_test_packet,
+        connection,
+    ));
+}
+
+fn push_process_under_test_event(process_under_test_event: ProcessUnderTestEvent) {
+    let sender = PROCESS_UNDER_TEST_EVENTS.get_or_init(|| {
+        let (sender, receiver) = channel::unbounded();
+        thread::spawn(move || {
+            for process_under_test_event in receiver {
+                match process_under_test_event {
+                    ProcessUnderTestEvent::Marker(process_under_test_packet, connection) => {
+                        read_marker_counters(process_under_test_packet, connection)
+                    }
+                    ProcessUnderTestEvent::Ready(event) => EVENT_QUEUE.push(event),
+                }
+            }
+        });
+        sender
     });
+    // The reader thread never stops, so sending can not fail
+    let _ = sender.send(process_under_test_event);
+}
+
+// Events still waiting for the reader thread
+fn process_under_test_events_pending() -> bool {
+    PROCESS_UNDER_TEST_EVENTS
+        .get()
+        .is_some_and(|sender| !sender.is_empty())
+}
+
+fn read_marker_counters(
+    process_under_test_packet: ProcessUnderTestPacket,
+    connection: Option<u64>,
+) {
+    // Read the CPU time and core energy as close to the marker as possible
+    let cpu_time = read_cpu_time(
+        process_under_test_packet.process_id,
+        process_under_test_packet.thread_id,
+    );
+    let core_energy = process_under_test_packet.cpu.and_then(read_core_energy);
+    let cpu_migrations = read_cpu_migrations(
+        process_under_test_packet.process_id,
+        process_under_test_packet.thread_id,
+    );
+
+    // Push the packet to the process under test packet queue
+    EVENT_QUEUE.push(Event::Packet(ReceivedPacket {
+        packet: process_under_test_packet,
+        synthetic: false,
+        connection,
+        cpu_time,
+        core_energy,
+        cpu_migrations,
+    }));
+}
+
+fn take_disconnected(
+    matches: impl Fn(&DisconnectedProcess) -> bool,
+) -> Option<DisconnectedProcess> {
+    let mut disconnected = DISCONNECTED.lock().unwrap();
+    let position = disconnected.iter().position(matches)?;
+    Some(disconnected.swap_remove(position))
+}
+
+#[cfg(target_os = "linux")]
+fn process_running(process_id: u32) -> bool {
+    std::path::Path::new(&format!("/proc/{}", process_id)).exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_running(_process_id: u32) -> bool {
+    true
+}
+
+fn finish_process_under_test(mut validator: ConnectionValidator, timestamp: u128) {
+    // The process has disconnected, stop the regions it left open
+    // Passed through the reader thread, so they follow the markers of the connection
+    for packet in validator.synthetic_stops(timestamp) {
+        push_process_under_test_event(ProcessUnderTestEvent::Ready(Event::Packet(
+            ReceivedPacket {
+                packet,
+                synthetic: true,
+                connection: None,
+                cpu_time: None,
+                core_energy: None,
+                cpu_migrations: None,
+            },
+        )));
+    }
+
+    if let Some(validation_report) = validator.into_report() {
+        if !validation_report.is_clean() {
+            println!(
+                "Process disconnected with invalid markers: {:?}",
+                validation_report
+            );
+        }
+        push_process_under_test_event(ProcessUnderTestEvent::Ready(Event::Report(
+            validation_report,
+        )));
+    }
+}
+
+// Processes under test using the shared memory transport hand their ring over the Unix socket,
+// it is read until the process exits and closes the connection
+#[cfg(target_os = "linux")]
+fn poll_shm_rings(settings: ShmRingSettings) {
+    use std::io::{ErrorKind, Read};
+
+    struct RingConnection {
+        connection: ShmRingConnection,
+        validator: ConnectionValidator,
+        reported_dropped: u64,
+    }
+
+    let (sender, receiver) = channel::unbounded();
+    if SHM_RINGS.set(sender).is_err() {
+        return;
+    }
+    println!("Accepting shared memory rings over the Unix socket");
+    let poll_interval = Duration::from_micros(settings.poll_interval_micros);
+
+    thread::spawn(move || {
+        let mut rings: Vec<RingConnection> = Vec::new();
+
+        loop {
+            rings.extend(receiver.try_iter().map(|connection| RingConnection {
+                connection,
+                validator: ConnectionValidator::default(),
+                reported_dropped: 0,
+            }));
+
+            rings.retain_mut(|ring_connection| {
+                let ring = &ring_connection.connection.ring;
+                let process_id = ring.process_id();
+
+                // Checked before reading, so the records written before the process exited are read
+                let finished = !matches!(
+                    (&ring_connection.connection.socket).read(&mut [0]),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock
+                );
+
+                while let Some(record) = ring.pop() {
+                    let process_under_test_packet = ProcessUnderTestPacket {
+                        id: record.id,
+                        process_id,
+                        thread_id: record.thread_id,
+                        operation: record.operation,
+                        timestamp: record.timestamp,
+                        cpu: record.cpu,
+                        work_count: record.work_count,
+                        attributes: Vec::new(),
+                        sequence: None,
+                    };
+                    ring_connection
+                        .validator
+                        .handle_packet(&process_under_test_packet);
+                    push_process_under_test_packet(process_under_test_packet, None);
+                }
+
+                let dropped = ring.dropped();
+                if dropped > ring_connection.reported_dropped {
+                    println!(
+                        "Process {} dropped {} markers, its shared memory ring is full",
+                        process_id,
+                        dropped - ring_connection.reported_dropped
+                    );
+                    ring_connection.reported_dropped = dropped;
+                }
+
+                if finished {
+                    let mut validator = std::mem::take(&mut ring_connection.validator);
+                    validator.set_dropped_markers(dropped);
+                    finish_process_under_test(validator, get_timestamp());
+                }
+                !finished
+            });
+
+            thread::sleep(poll_interval);
+        }
+    });
+}
+
+#[cfg(not(target_os = "linux"))]
+fn poll_shm_rings(_settings: ShmRingSettings) {
+    println!("Shared memory rings are not supported on this platform");
 }
 
 async fn handle_client_connection(
-    client_tcpstreams: Arc<Mutex<Vec<std::net::TcpStream>>>,
+    client_tcpstreams: ClientConnections,
     mut socket: tokio::net::TcpStream,
+    baseline_settings: BaselineSettings,
+    enabled_domains: &[String],
 ) {
     let mut buf = Vec::new();
     while !buf.ends_with(NEEDLE) && buf.len() < MAX_REPO_SIZE {
@@ -124,30 +841,70 @@ async fn handle_client_connection(
 
     buf = buf[0..buf.len() - NEEDLE.len()].to_vec();
 
-    let repo = match String::from_utf8(buf) {
-        Ok(repo) => repo.trim_matches(char::from(0)).to_string(),
+    let request = match String::from_utf8(buf) {
+        Ok(request) => request.trim_matches(char::from(0)).to_string(),
         Err(e) => {
             println!("Failed to parse repo: {:?}", e);
             return;
         }
     };
 
-    println!("Received repo: {:?}", repo);
+    let (repo, options) = parse_client_request(&request);
 
-    client_tcpstreams
-        .lock()
-        .unwrap()
-        .push(socket.into_std().unwrap());
+    // Every message is a frame in the client's encoding, starting with the hello
+    let hello = ClientMessage::Hello(ServerHello {
+        protocol_version: protocol::CLIENT_PROTOCOL_VERSION,
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        cpu_vendor: cpu_vendor().to_string(),
+        domains: enabled_domains.to_vec(),
+        encoding: options.encoding,
+    });
+    let hello_frame = options.encoding.encode_frame(&hello).unwrap();
+    if let Err(err) = socket.write_all(&hello_frame).await {
+        println!("Could not send hello to client, Error: {:?}", err);
+        return;
+    }
+
+    // Energy queries are answered once, without registering the client
+    if let Some(query) = repo.strip_prefix(ENERGY_QUERY_PREFIX) {
+        match parse_energy_query(query) {
+            Some((start_timestamp, stop_timestamp)) => EVENT_QUEUE.push(Event::EnergyQuery {
+                start_timestamp,
+                stop_timestamp,
+                stream: socket.into_std().unwrap(),
+                encoding: options.encoding,
+            }),
+            None => println!("Failed to parse energy query: {:?}", query),
+        }
+        return;
+    }
+
+    println!("Received repo: {:?}, options: {:?}", repo, options);
+
+    let baseline_requested = options.baseline;
+    client_tcpstreams.lock().unwrap().push(ClientConnection {
+        stream: socket.into_std().unwrap(),
+        options,
+    });
 
     if repo == "none" {
         println!("No repo provided, client assigned as observer");
+        if baseline_requested {
+            thread::spawn(move || measure_idle_power(baseline_settings.millis));
+        }
         return;
     }
 
     // Thread for building and running process
     thread::spawn(move || {
+        if baseline_requested || baseline_settings.before_jobs {
+            measure_idle_power(baseline_settings.millis);
+        }
+
+        EVENT_QUEUE.push(Event::JobStarted);
+
         // build and start process
-        let res = GitBuild {}.build(repo);
+        let res = GitBuild {}.build(repo.clone());
         match res {
             Ok(_) => {}
             Err(e) => {
@@ -156,15 +913,24 @@ async fn handle_client_connection(
         }
 
         // waiting for measurements to be sent
-        while !PROCESS_UNDER_TEST_PACKET_QUEUE.is_empty() {
+        while !EVENT_QUEUE.is_empty() || process_under_test_events_pending() {
+            thread::sleep(Duration::from_secs(1));
+        }
+
+        // The summary is sent once all regions of the job are handled
+        EVENT_QUEUE.push(Event::JobFinished { repo });
+        while !EVENT_QUEUE.is_empty() {
             thread::sleep(Duration::from_secs(1));
         }
 
         // Disconnecting client measurement is done
         // TODO this can break with multiple clients are connected.
         match client_tcpstreams.lock().unwrap().pop() {
-            Some(s) => {
-                s.shutdown(std::net::Shutdown::Both).unwrap();
+            Some(client_connection) => {
+                client_connection
+                    .stream
+                    .shutdown(std::net::Shutdown::Both)
+                    .unwrap();
             }
             None => {
                 println!("Could not disconnect client, client might already be disconnected.");
@@ -173,9 +939,63 @@ async fn handle_client_connection(
     });
 }
 
+// Wait while the idle power is measured, the baseline itself is computed by the sampling side
+fn measure_idle_power(baseline_millis: u64) {
+    println!("Measuring idle power for {} ms", baseline_millis);
+
+    let start_timestamp = get_timestamp();
+    thread::sleep(Duration::from_millis(baseline_millis));
+    EVENT_QUEUE.push(Event::Baseline {
+        start_timestamp,
+        stop_timestamp: get_timestamp(),
+    });
+}
+
+// Splits the request into the repo (with an optional branch) and the client options
+fn parse_client_request(request: &str) -> (String, ClientOptions) {
+    let mut options = ClientOptions::default();
+    let mut repo = Vec::new();
+
+    for part in request.split_whitespace() {
+        match part.strip_prefix(CLIENT_OPTION_PREFIX) {
+            Some("raw") => options.raw_packets = true,
+            Some("baseline") => options.baseline = true,
+            Some("statistics") => options.statistics = true,
+            Some("timeline") => options.timeline = true,
+            Some(option) if option.starts_with(ENCODING_OPTION_PREFIX) => {
+                let name = &option[ENCODING_OPTION_PREFIX.len()..];
+                match ClientEncoding::from_name(name) {
+                    Some(encoding) => options.encoding = encoding,
+                    None => println!("Ignoring unknown encoding: {}", name),
+                }
+            }
+            Some(option) => println!("Ignoring unknown client option: {}", option),
+            None => repo.push(part),
+        }
+    }
+
+    (repo.join(" "), options)
+}
+
+// The start and stop timestamps of an energy query, in nanoseconds since the Unix epoch
+fn parse_energy_query(query: &str) -> Option<(u128, u128)> {
+    let mut timestamps = query
+        .split_whitespace()
+        .map(|timestamp| timestamp.parse().ok());
+    let start_timestamp = timestamps.next()??;
+    let stop_timestamp = timestamps.next()??;
+    if timestamps.next().is_some() {
+        return None;
+    }
+    Some((start_timestamp, stop_timestamp))
+}
+
 fn send_packet_to_clients<M: Measurement<(RaplMeasurementJoules, u32)>>(
-    client_connections: Arc<Mutex<Vec<std::net::TcpStream>>>,
+    client_connections: ClientConnections,
     client_packet_queue_cycle: u64,
+    reliability_threshold: f64,
+    correct_overhead: bool,
+    timeline_resolution_micros: u64,
     measurement: &mut M,
 ) {
     // Create duration from the config
@@ -190,36 +1010,248 @@ fn send_packet_to_clients<M: Measurement<(RaplMeasurementJoules, u32)>>(
     }
      */
 
+    let mut region_tracker = RegionTracker::new(counter_wrap_joules(), reliability_threshold)
+        .correct_overhead(correct_overhead);
+    let mut timeline_cursor = TimelineCursor::new(timeline_resolution_micros as u128 * 1000);
+    let mut region_aggregator = RegionAggregator::default();
+    let mut session_tracker = SessionTracker::default();
     let mut client_packets = Vec::new();
+    let mut client_messages = Vec::new();
+
+    // Markers of a thread can arrive out of order over separate connections, so they are held back
+    // for a cycle and delivered in timestamp order. Reports are held back with the packets they cover
+    let hold_back_nanos = duration.as_nanos();
+    let mut held_packets: Vec<ReceivedPacket> = Vec::new();
+    let mut held_reports: Vec<(u128, ValidationReport)> = Vec::new();
 
     loop {
-        let mut process_under_test_packets = VecDeque::new();
+        let mut validation_reports = Vec::new();
+        let mut baselines = Vec::new();
+        let mut finished_jobs = Vec::new();
+        let mut energy_queries = Vec::new();
 
         // Extract packets from processes under test initially to allow the sampler getting ahead
-        while let Some(process_under_test_packet) = PROCESS_UNDER_TEST_PACKET_QUEUE.pop() {
-            process_under_test_packets.push_back(process_under_test_packet);
+        while let Some(process_under_test_event) = EVENT_QUEUE.pop() {
+            match process_under_test_event {
+                Event::Packet(received_packet) => {
+                    // Events are queued in the order each connection received them
+                    let process_under_test_packet = &received_packet.packet;
+                    if let (Some(connection), Some(sequence)) = (
+                        received_packet.connection,
+                        process_under_test_packet.sequence,
+                    ) {
+                        match session_tracker.handle_sequence(
+                            connection,
+                            process_under_test_packet.process_id,
+                            sequence,
+                        ) {
+                            Some(0) => {}
+                            Some(lost) => println!(
+                                "Lost {} markers from process {}",
+                                lost, process_under_test_packet.process_id
+                            ),
+                            // Sent again after a lost connection, it would be paired twice
+                            None => {
+                                println!(
+                                    "Dropping marker {} of process {}, it was received before",
+                                    sequence, process_under_test_packet.process_id
+                                );
+                                continue;
+                            }
+                        }
+                    }
+                    held_packets.push(received_packet)
+                }
+                Event::Report(validation_report) => {
+                    held_reports.push((get_timestamp(), validation_report))
+                }
+                Event::Session(connection, process_session) => {
+                    session_tracker.add(connection, process_session)
+                }
+                Event::Disconnected(connection) => session_tracker.remove(connection),
+                Event::Baseline {
+                    start_timestamp,
+                    stop_timestamp,
+                } => baselines.push((start_timestamp, stop_timestamp)),
+                // Statistics and sessions are kept per job
+                Event::JobStarted => {
+                    region_aggregator.clear();
+                    session_tracker.clear();
+                }
+                Event::JobFinished { repo } => finished_jobs.push(repo),
+                Event::EnergyQuery {
+                    start_timestamp,
+                    stop_timestamp,
+                    stream,
+                    encoding,
+                } => energy_queries.push((start_timestamp, stop_timestamp, stream, encoding)),
+            }
+        }
+
+        // Markers taken a cycle ago are not passed by markers still on their way. A finished job
+        // gets all of its markers. The sort is stable, keeping the order of equal timestamps
+        let released_before = if finished_jobs.is_empty() {
+            get_timestamp().saturating_sub(hold_back_nanos)
+        } else {
+            u128::MAX
+        };
+        held_packets.sort_by_key(|received_packet| received_packet.packet.timestamp);
+        let released = held_packets
+            .partition_point(|received_packet| received_packet.packet.timestamp <= released_before);
+        let process_under_test_packets: VecDeque<ReceivedPacket> =
+            held_packets.drain(..released).collect();
+        // Reports arrive after the markers of their connection, which were taken before
+        let released = held_reports
+            .partition_point(|(received_timestamp, _)| *received_timestamp <= released_before);
+        validation_reports.extend(
+            held_reports
+                .drain(..released)
+                .map(|(_, validation_report)| validation_report),
+        );
+
+        for (start_timestamp, stop_timestamp, mut stream, encoding) in energy_queries {
+            let result = measurement.query_energy(start_timestamp, stop_timestamp);
+            let serialized_packet =
+                encode_messages(encoding, &[&ClientMessage::EnergyQuery(result)]);
+            let sent = stream
+                .set_nonblocking(false)
+                .and_then(|_| stream.write_all(&serialized_packet));
+            if let Err(err) = sent {
+                println!("Could not answer energy query, Error: {:?}", err);
+            }
+            let _ = stream.shutdown(Shutdown::Both);
         }
 
         // TODO: Consider sleeping here if the sampler is too slow, i.e. unable to find a measurement for the current packet due to time difference
 
-        if process_under_test_packets.is_empty() {
-            //keeping the sampler alive
-            measurement.get_measurement(
-                SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_nanos(),
-            );
+        // Baselines are measured before the jobs they are used for
+        for (start_timestamp, stop_timestamp) in baselines {
+            let Some(baseline) = measure_baseline(
+                measurement,
+                start_timestamp,
+                stop_timestamp,
+                counter_wrap_joules(),
+            ) else {
+                println!("No baseline is available, the samples of the idle period are missing");
+                continue;
+            };
+            println!("Measured idle power: {:?}", baseline.watts);
+            region_tracker.set_baseline(baseline.clone());
+            client_messages.push(ClientMessage::Baseline(baseline));
+        }
+
+        // The power timeline is only followed while a client wants it
+        let timeline_wanted = client_connections
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|client_connection| client_connection.options.timeline);
+        if timeline_wanted {
+            if let Some((start_timestamp, watts)) =
+                timeline_cursor.advance(measurement, get_timestamp(), counter_wrap_joules())
+            {
+                client_messages.push(ClientMessage::PowerTimeline(PowerTimeline {
+                    start_timestamp,
+                    resolution_nanos: timeline_cursor.resolution_nanos(),
+                    watts,
+                    markers: process_under_test_packets
+                        .iter()
+                        .map(|received_packet| TimelineMarker {
+                            id: received_packet.packet.id.clone(),
+                            process_id: received_packet.packet.process_id,
+                            thread_id: received_packet.packet.thread_id,
+                            operation: received_packet.packet.operation,
+                            timestamp: received_packet.packet.timestamp,
+                        })
+                        .collect(),
+                }));
+            }
         } else {
+            timeline_cursor.reset();
+        }
+
+        if !process_under_test_packets.is_empty()
+            || !validation_reports.is_empty()
+            || !finished_jobs.is_empty()
+            || !client_messages.is_empty()
+        {
             // Create client packets
             create_client_packets(process_under_test_packets, measurement, &mut client_packets);
 
+            // Pair the packets into regions, keeping the raw packets for the clients that want them
+            let mut regions_added = false;
+            for client_packet in client_packets.drain(..) {
+                if let ProcessUnderTestPacketOperation::Mark =
+                    client_packet.process_under_test_packet.operation
+                {
+                    client_messages.push(ClientMessage::Mark(
+                        region_tracker.mark_event(&client_packet),
+                    ));
+                }
+                let region_results = region_tracker.handle_packet(&client_packet);
+                client_messages.push(ClientMessage::Packet(client_packet));
+                for region_result in region_results {
+                    client_messages.push(match region_result {
+                        Ok(region_result) => {
+                            region_aggregator.add(&region_result);
+                            regions_added = true;
+                            ClientMessage::Region(Box::new(region_result))
+                        }
+                        Err(region_error) => {
+                            println!("Region error: {:?}", region_error);
+                            ClientMessage::RegionError(region_error)
+                        }
+                    });
+                }
+            }
+
+            client_messages.extend(
+                region_tracker
+                    .take_overheads()
+                    .into_iter()
+                    .map(ClientMessage::InstrumentationOverhead),
+            );
+
+            // Reports are sent after the packets of the connection they cover
+            client_messages.extend(
+                validation_reports
+                    .into_iter()
+                    .map(ClientMessage::ValidationReport),
+            );
+
+            client_messages.extend(finished_jobs.into_iter().map(|repo| {
+                ClientMessage::JobSummary(JobSummary {
+                    repo,
+                    statistics: region_aggregator.statistics(),
+                    sessions: session_tracker.trees(),
+                })
+            }));
+
             // Get a lock on the client connections
             let mut client_connections_lock = client_connections.lock().unwrap();
 
-            if !client_connections_lock.is_empty() && !client_packets.is_empty() {
-                client_connections_lock.retain_mut(|conn| {
-                    let serialized_packet = serde_json::to_vec(&client_packets).unwrap();
+            // Bootstrapping is costly, so the statistics are only computed if a client wants them
+            if regions_added
+                && client_connections_lock
+                    .iter()
+                    .any(|client_connection| client_connection.options.statistics)
+            {
+                client_messages.push(ClientMessage::Statistics(region_aggregator.statistics()));
+            }
+
+            if !client_connections_lock.is_empty() && !client_messages.is_empty() {
+                client_connections_lock.retain_mut(|client_connection| {
+                    let conn = &mut client_connection.stream;
+                    let client_messages: Vec<&ClientMessage> = client_messages
+                        .iter()
+                        .filter(|client_message| client_connection.options.wants(client_message))
+                        .collect();
+                    if client_messages.is_empty() {
+                        return true;
+                    }
+
+                    let serialized_packet =
+                        encode_messages(client_connection.options.encoding, &client_messages);
 
                     // blocks if the packets is over 1 Kb
                     if serialized_packet.len() > 1000 {
@@ -234,7 +1266,7 @@ fn send_packet_to_clients<M: Measurement<(RaplMeasurementJoules, u32)>>(
                         }
                     }
                     // sending packets
-                    match send_packet(conn, &serialized_packet) {
+                    match conn.write_all(&serialized_packet) {
                         Ok(_) => {
                             conn.set_nonblocking(true).unwrap();
                             true
@@ -247,7 +1279,7 @@ fn send_packet_to_clients<M: Measurement<(RaplMeasurementJoules, u32)>>(
                         }
                     }
                 });
-                client_packets.clear();
+                client_messages.clear();
             }
         }
 
@@ -256,33 +1288,134 @@ fn send_packet_to_clients<M: Measurement<(RaplMeasurementJoules, u32)>>(
     }
 }
 
-fn send_packet(
-    conn: &mut std::net::TcpStream,
-    serialized_packet: &Vec<u8>,
-) -> Result<(), std::io::Error> {
-    conn.write_all(serialized_packet)?;
-    conn.write_all(MEASUREMENTS_DELIMITER)
+// One frame per message, messages that can not be encoded are left out
+fn encode_messages(encoding: ClientEncoding, client_messages: &[&ClientMessage]) -> Vec<u8> {
+    let mut serialized_packet = Vec::new();
+    for client_message in client_messages {
+        match encoding.encode_frame(client_message) {
+            Ok(frame) => serialized_packet.extend_from_slice(&frame),
+            Err(err) => println!("Could not encode message for client: {}", err),
+        }
+    }
+    serialized_packet
 }
 
 fn create_client_packets<M: Measurement<(RaplMeasurementJoules, u32)>>(
-    mut process_under_test_packets: VecDeque<ProcessUnderTestPacket>,
+    mut process_under_test_packets: VecDeque<ReceivedPacket>,
     measurement: &mut M,
     client_packets: &mut Vec<ClientPacket>,
 ) {
-    let timestamps: Vec<u128> = process_under_test_packets
-        .iter()
-        .map(|x| x.timestamp)
-        .collect();
-    let measurements = measurement.get_multiple_measurements(&timestamps);
+    let window_seconds = POWER_WINDOW_NANOS as f64 / 1_000_000_000.0;
 
     // handling multiple packets at a time
-    for x in 0..process_under_test_packets.len() {
-        let process_under_test_packet = process_under_test_packets.pop_front().unwrap();
+    while let Some(received_packet) = process_under_test_packets.pop_front() {
+        let timestamp = received_packet.packet.timestamp;
+        // Markers from before the oldest retained sample, e.g. buffered while the server was down,
+        // can not be measured
+        let Some((rapl_measurement, pkg_overflow)) = measurement.try_get_measurement(timestamp)
+        else {
+            println!(
+                "Dropping marker {:?} of process {}, there is no sample at its timestamp {}",
+                received_packet.packet.id, received_packet.packet.process_id, timestamp
+            );
+            continue;
+        };
+
+        // The power just before the packet, used to estimate how far its counters may be off.
+        // Packets too close to the oldest sample have no uncertainty
+        let uncertainty = timestamp
+            .checked_sub(POWER_WINDOW_NANOS)
+            .and_then(|window_timestamp| measurement.try_get_measurement(window_timestamp))
+            .zip(measurement.get_sample_spacing(timestamp))
+            .map(|(window_measurement, sample_spacing)| {
+                let watts = region_energy(
+                    (&window_measurement.0, window_measurement.1),
+                    (&rapl_measurement, pkg_overflow),
+                    counter_wrap_joules(),
+                )
+                .map(|joules| joules / window_seconds);
+                marker_uncertainty(&watts, sample_spacing, energy_unit_joules())
+            });
         let client_packet = ClientPacket {
-            process_under_test_packet,
-            rapl_measurement: measurements[x].0.clone(),
-            pkg_overflow: measurements[x].1,
+            process_under_test_packet: received_packet.packet,
+            rapl_measurement,
+            pkg_overflow,
+            synthetic: received_packet.synthetic,
+            cpu_time: received_packet.cpu_time,
+            core_energy: received_packet.core_energy,
+            cpu_migrations: received_packet.cpu_migrations,
+            uncertainty,
         };
         client_packets.push(client_packet);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_markers_outside_of_the_window_are_rejected() {
+        let received_timestamp = 1_000_000_000_000;
+        let marker_window = 1_000_000_000;
+
+        let packet = parse_text_marker_in_window("start x", received_timestamp, marker_window)
+            .unwrap()
+            .unwrap();
+        assert_eq!(packet.timestamp, received_timestamp);
+        assert!(parse_text_marker_in_window(
+            "stop x @999500000000",
+            received_timestamp,
+            marker_window
+        )
+        .unwrap()
+        .is_some());
+
+        assert_eq!(
+            parse_text_marker_in_window("start x @1", received_timestamp, marker_window)
+                .unwrap_err(),
+            TextMarkerError::OutsideWindow(1)
+        );
+        assert_eq!(
+            parse_text_marker_in_window(
+                "mark x 1 1 @1000000000001",
+                received_timestamp,
+                marker_window
+            )
+            .unwrap_err(),
+            TextMarkerError::OutsideWindow(1_000_000_000_001)
+        );
+    }
+
+    #[test]
+    fn test_text_lines_longer_than_a_frame_close_the_connection() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let (mut script, server) = tokio::io::duplex(4096);
+            handle_text_connection(server, 0);
+
+            script
+                .write_all(&vec![b'a'; protocol::MAX_FRAME_LENGTH + 1])
+                .await
+                .unwrap();
+            let mut answer = String::new();
+            script.read_to_string(&mut answer).await.unwrap();
+            assert_eq!(answer, format!("error: {}\n", TextMarkerError::TooLong));
+        });
+    }
+
+    #[test]
+    fn test_parse_energy_query() {
+        assert_eq!(parse_energy_query("100 200"), Some((100, 200)));
+        // Reversed timestamps are parsed, and rejected when the query is answered
+        assert_eq!(parse_energy_query(" 200  100 "), Some((200, 100)));
+    }
+
+    #[test]
+    fn test_parse_malformed_energy_query() {
+        assert_eq!(parse_energy_query(""), None);
+        assert_eq!(parse_energy_query("100"), None);
+        assert_eq!(parse_energy_query("100 200 300"), None);
+        assert_eq!(parse_energy_query("100 later"), None);
+        assert_eq!(parse_energy_query("-100 200"), None);
+    }
+}
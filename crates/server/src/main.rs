@@ -1,12 +1,23 @@
-use crate::{component_def::Listener, listener::ListenerImplem, measurement::RaplSampler};
+use crate::{
+    component_def::Listener,
+    listener::{ListenerImplem, ShmRingSettings, UnixSocketSettings},
+    measurement::RaplSampler,
+};
 use config::Config;
 use std::{fs, sync::Arc, thread::sleep};
 
+mod baseline;
 mod build;
 mod component_def;
 mod config;
+mod cpu_time;
 mod listener;
 mod measurement;
+mod regions;
+mod sessions;
+mod timeline;
+mod uncertainty;
+mod validation;
 
 fn main() {
     //getting config
@@ -15,6 +26,21 @@ fn main() {
     let config: Arc<Config> =
         Arc::new(toml::from_str(&config_file_data).expect("Failed to parse config"));
 
+    // The baseline is computed from the retained samples after it has been measured
+    let max_baseline_millis = config
+        .thor
+        .max_sample_age_millis
+        .saturating_sub(config.thor.client_packet_queue_cycle_millis + 1);
+    let baseline_millis = if config.thor.baseline_millis > max_baseline_millis {
+        println!(
+            "baseline_millis plus client_packet_queue_cycle_millis must be less than max_sample_age_millis, measuring the baseline for {} ms",
+            max_baseline_millis
+        );
+        max_baseline_millis
+    } else {
+        config.thor.baseline_millis
+    };
+
     let mut measure = RaplSampler::new(
         config.thor.max_sample_age_millis as u128,
         config.thor.sampling_interval_micros,
@@ -26,6 +52,24 @@ fn main() {
     let listen = ListenerImplem {
         ip: config.thor.server_ip.clone(),
         client_packet_queue_cycle: config.thor.client_packet_queue_cycle_millis,
+        baseline_millis,
+        baseline_before_jobs: config.thor.baseline_before_jobs,
+        reliability_threshold: config.thor.reliability_threshold,
+        correct_overhead: config.thor.correct_overhead,
+        timeline_resolution_micros: config.thor.timeline_resolution_micros,
+        enabled_domains: config.enabled_domains(),
+        max_sample_age: config.thor.max_sample_age_millis,
+        unix_socket: config
+            .thor
+            .unix_socket_path
+            .clone()
+            .map(|path| UnixSocketSettings {
+                path,
+                mode: config.thor.unix_socket_mode,
+            }),
+        shm_rings: config.thor.shm_rings.then(|| ShmRingSettings {
+            poll_interval_micros: config.thor.shm_poll_interval_micros,
+        }),
     };
     listen.start_listening(&mut measure).unwrap();
 }
@@ -1,141 +1,182 @@
-use crate::component_def::Measurement;
+use crate::{component_def::Measurement, regions::region_energy};
 use anyhow::Result;
-use crossbeam::queue::SegQueue;
-use rangemap::RangeMap;
 use std::{
     thread,
     time::{Duration, SystemTime},
 };
 use thor_lib::{
-    convert_to_joules, read_rapl_msr_registers, RaplMeasurement, RaplMeasurementJoules,
+    convert_to_joules, counter_wrap_joules, read_rapl_msr_registers,
+    sample_buffer::{sample_ring_buffer, Sample, SampleReader, SampleWriter},
+    RaplMeasurementJoules,
 };
+use thor_shared::EnergyQueryResult;
 
-static SAMPLING_THREAD_DATA: SegQueue<(RaplMeasurement, u128)> = SegQueue::new();
+// Samples are valid for one sampling interval plus 20 ms to account for possible delay
+const SAMPLE_DELAY_TOLERANCE_NANOS: u128 = 20_000_000;
 
 pub struct RaplSampler {
-    pub max_sample_age: u128,
-    range_map: RangeMap<u128, (RaplMeasurement, u32)>,
+    samples: SampleReader,
     sampling_interval: u64,
-    pkg_overflow: u32,
-    last_pkg: u64,
 }
 
 impl Measurement<(RaplMeasurementJoules, u32)> for RaplSampler {
-    fn get_measurement(&mut self, timestamp: u128) -> (RaplMeasurementJoules, u32) {
-        self.update_range_map(timestamp);
-
-        let result = self.range_map.get(&timestamp).expect(
-            format!(
-                "No measurement found for timestamp: {}, max: {:?}",
-                timestamp,
-                self.range_map.last_range_value()
-            )
-            .as_str(),
-        );
+    fn try_get_measurement(&mut self, timestamp: u128) -> Option<(RaplMeasurementJoules, u32)> {
+        self.try_find_sample(timestamp)
+            .map(|sample| (convert_to_joules(sample.measurement), sample.pkg_overflow))
+    }
 
-        // converting to joules
-        (convert_to_joules(result.0.clone()), result.1)
+    fn get_sample_spacing(&mut self, timestamp: u128) -> Option<u128> {
+        match self.samples.find_with_next(timestamp)? {
+            (sample, Some(next)) => Some(next.timestamp - sample.timestamp),
+            // The next sample is not taken yet, assume it arrives one interval later
+            (sample, None) => {
+                Some(timestamp - sample.timestamp + self.sampling_interval as u128 * 1000)
+            }
+        }
     }
 
-    fn get_multiple_measurements(
+    /// Energy used between two past timestamps, computed like the energy of a region.
+    fn query_energy(
         &mut self,
-        timestamps: &Vec<u128>,
-    ) -> Vec<(RaplMeasurementJoules, u32)> {
-        let mut result = Vec::new();
-
-        // updating rangemap using the first timestamp
-        self.update_range_map(timestamps[0]);
-
-        // find measurements
-        for timestamp in timestamps {
-            let measurement = self.range_map.get(&timestamp).expect(
-                format!(
-                    "No measurement found for timestamp: {}, max: {:?}",
-                    timestamp,
-                    self.range_map.last_range_value()
-                )
-                .as_str(),
-            );
-
-            // converting to joules
-            result.push((
-                convert_to_joules(measurement.0.clone()),
-                measurement.1.clone(),
-            ));
+        start_timestamp: u128,
+        stop_timestamp: u128,
+    ) -> Result<EnergyQueryResult, String> {
+        if start_timestamp > stop_timestamp {
+            return Err("The start timestamp is after the stop timestamp".to_string());
+        }
+        if stop_timestamp > get_timestamp() {
+            return Err("The stop timestamp is in the future".to_string());
         }
 
-        result
+        let outside_window = || "The timestamps are outside of the retained samples".to_string();
+        let start = self
+            .try_get_measurement(start_timestamp)
+            .ok_or_else(outside_window)?;
+        let stop = self
+            .try_get_measurement(stop_timestamp)
+            .ok_or_else(outside_window)?;
+
+        Ok(EnergyQueryResult {
+            start_timestamp,
+            stop_timestamp,
+            energy: region_energy(
+                (&start.0, start.1),
+                (&stop.0, stop.1),
+                counter_wrap_joules(),
+            ),
+        })
     }
 }
 
 impl RaplSampler {
     pub fn new(max_sample_age: u128, sampling_interval: u64) -> RaplSampler {
+        let (writer, samples) =
+            sample_ring_buffer(sample_capacity(max_sample_age, sampling_interval));
+
         let result = RaplSampler {
-            max_sample_age,
-            range_map: RangeMap::new(),
+            samples,
             sampling_interval,
-            pkg_overflow: 0,
-            last_pkg: 0,
         };
-        result.start_sampling(sampling_interval).unwrap();
+        result.start_sampling(writer).unwrap();
         result
     }
 
-    fn start_sampling(&self, sampling_interval: u64) -> Result<()> {
+    fn start_sampling(&self, writer: SampleWriter) -> Result<()> {
+        let sampling_interval = self.sampling_interval;
         thread::spawn(move || {
-            rapl_sampling_thread(sampling_interval);
+            rapl_sampling_thread(writer, sampling_interval);
         });
         Ok(())
     }
 
-    fn update_range_map(&mut self, timestamp: u128) {
-        // add new measurements
-        while let Some((measurement, time)) = SAMPLING_THREAD_DATA.pop() {
-            // finding overflows, by matching cpu type and checking pkg
-            match measurement {
-                RaplMeasurement::Intel(ref intel_rapl_registers) => {
-                    if self.last_pkg > intel_rapl_registers.pkg {
-                        self.pkg_overflow += 1;
-                    }
-                    self.last_pkg = intel_rapl_registers.pkg;
-                }
-                RaplMeasurement::AMD(ref amd_rapl_registers) => {
-                    if self.last_pkg > amd_rapl_registers.pkg {
-                        self.pkg_overflow += 1;
-                    }
-                    self.last_pkg = amd_rapl_registers.pkg;
-                }
-            }
+    fn try_find_sample(&self, timestamp: u128) -> Option<Sample> {
+        let max_delay = self.sampling_interval as u128 * 1000 + SAMPLE_DELAY_TOLERANCE_NANOS;
 
-            self.range_map.insert(
-                time..time + (self.sampling_interval * 1000 + 20_000_000) as u128, //Added 20 ms to account for possible delay
-                (measurement, self.pkg_overflow),
-            );
-        }
-
-        //remove old measurements (max_sample_age is in milliseconds, converting to nanoseconds)
-        self.range_map
-            .remove(0..timestamp - (self.max_sample_age * 1_000_000));
+        self.samples
+            .find(timestamp)
+            .filter(|sample| timestamp - sample.timestamp <= max_delay)
     }
 }
 
-fn rapl_sampling_thread(sampling_interval: u64) {
+// Number of samples needed to cover max_sample_age (in milliseconds) when sampling every sampling_interval (in microseconds)
+fn sample_capacity(max_sample_age: u128, sampling_interval: u64) -> usize {
+    let capacity = max_sample_age * 1000 / sampling_interval.max(1) as u128;
+    (capacity as usize).max(2)
+}
+
+fn rapl_sampling_thread(mut writer: SampleWriter, sampling_interval: u64) {
     // Loop and sample the RAPL data
     loop {
-        // Grab the RAPL data and the timestamp, then push it to the queue
+        // Grab the RAPL data and the timestamp, then push it to the ring buffer
         let rapl_measurement = read_rapl_msr_registers();
         let timestamp = get_timestamp();
 
-        SAMPLING_THREAD_DATA.push((rapl_measurement, timestamp));
+        writer.push(timestamp, &rapl_measurement);
 
         // Sleep for the sampling interval
         thread::sleep(Duration::from_micros(sampling_interval));
     }
 }
 
-fn get_timestamp() -> u128 {
+pub fn get_timestamp() -> u128 {
     SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
         .as_nanos()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use thor_lib::{AmdRaplRegisters, RaplMeasurement};
+
+    // A sampler over the given sample timestamps, without a sampling thread
+    fn sampler(timestamps: &[u128]) -> RaplSampler {
+        let (mut writer, samples) = sample_ring_buffer(timestamps.len() + 1);
+        for &timestamp in timestamps {
+            writer.push(
+                timestamp,
+                &RaplMeasurement::AMD(AmdRaplRegisters { core: 0, pkg: 0 }),
+            );
+        }
+        RaplSampler {
+            samples,
+            sampling_interval: 1000,
+        }
+    }
+
+    #[test]
+    fn test_query_energy_rejects_reversed_timestamps() {
+        let now = get_timestamp();
+        let result = sampler(&[now - 2_000_000, now - 1_000_000])
+            .query_energy(now - 1_000_000, now - 2_000_000);
+        assert_eq!(
+            result.unwrap_err(),
+            "The start timestamp is after the stop timestamp"
+        );
+    }
+
+    #[test]
+    fn test_query_energy_rejects_future_timestamps() {
+        let now = get_timestamp();
+        let result = sampler(&[now]).query_energy(now, now + 1_000_000_000);
+        assert_eq!(result.unwrap_err(), "The stop timestamp is in the future");
+    }
+
+    #[test]
+    fn test_query_energy_rejects_timestamps_outside_of_the_window() {
+        let now = get_timestamp();
+        let result = sampler(&[now - 1_000_000, now]).query_energy(now - 2_000_000, now);
+        assert_eq!(
+            result.unwrap_err(),
+            "The timestamps are outside of the retained samples"
+        );
+    }
+
+    #[test]
+    fn test_sample_spacing_without_an_earlier_sample() {
+        let mut sampler = sampler(&[1_000, 2_000]);
+        assert_eq!(sampler.get_sample_spacing(500), None);
+        assert_eq!(sampler.get_sample_spacing(1_500), Some(1_000));
+    }
+}
@@ -0,0 +1,571 @@
+use crate::{baseline::energy_above_baseline, cpu_time::cpu_time_share, uncertainty::is_reliable};
+use std::collections::HashMap;
+use thor_lib::{AmdRaplRegistersJoules, IntelRaplRegistersJoules, RaplMeasurementJoules};
+use thor_shared::{
+    Baseline, ClientPacket, CpuTime, InstrumentationOverhead, MarkEvent,
+    ProcessUnderTestPacketOperation, RegionError, RegionResult, CALIBRATION_PAIR_ID,
+    CALIBRATION_REGION_ID,
+};
+
+// Regions are nested per process and thread
+type ThreadKey = (u32, usize);
+
+struct OpenRegion {
+    id: String,
+    attributes: Vec<(String, String)>,
+    timestamp: u128,
+    rapl_measurement: RaplMeasurementJoules,
+    pkg_overflow: u32,
+    cpu_time: Option<CpuTime>,
+    cpu: Option<u32>,
+    core_energy: Option<f64>,
+    cpu_migrations: Option<u64>,
+    uncertainty: Option<RaplMeasurementJoules>,
+    // Inclusive energy of the regions nested directly inside this one
+    children_energy: Option<RaplMeasurementJoules>,
+    // Number of regions nested at any depth inside this one
+    descendants: u64,
+}
+
+/// Pairs Start and Stop packets into region results, keeping a stack of open regions per thread.
+pub struct RegionTracker {
+    region_stacks: HashMap<ThreadKey, Vec<OpenRegion>>,
+    counter_wrap_joules: f64,
+    reliability_threshold: f64,
+    baseline: Option<Baseline>,
+    // Latest calibrated overhead per process
+    overheads: HashMap<u32, InstrumentationOverhead>,
+    new_overheads: Vec<InstrumentationOverhead>,
+    correct_overhead: bool,
+}
+
+impl RegionTracker {
+    pub fn new(counter_wrap_joules: f64, reliability_threshold: f64) -> RegionTracker {
+        RegionTracker {
+            region_stacks: HashMap::new(),
+            counter_wrap_joules,
+            reliability_threshold,
+            baseline: None,
+            overheads: HashMap::new(),
+            new_overheads: Vec::new(),
+            correct_overhead: false,
+        }
+    }
+
+    /// Subtract the calibrated instrumentation overhead from the regions of processes that ran a calibration.
+    pub fn correct_overhead(mut self, correct_overhead: bool) -> RegionTracker {
+        self.correct_overhead = correct_overhead;
+        self
+    }
+
+    /// The overheads calibrated since the last call.
+    pub fn take_overheads(&mut self) -> Vec<InstrumentationOverhead> {
+        std::mem::take(&mut self.new_overheads)
+    }
+
+    /// Use the baseline for the regions stopped from now on.
+    pub fn set_baseline(&mut self, baseline: Baseline) {
+        self.baseline = Some(baseline);
+    }
+
+    /// The mark event of a Mark packet, placed within the regions open on its thread.
+    pub fn mark_event(&self, client_packet: &ClientPacket) -> MarkEvent {
+        let packet = &client_packet.process_under_test_packet;
+        MarkEvent {
+            id: packet.id.clone(),
+            attributes: packet.attributes.clone(),
+            process_id: packet.process_id,
+            thread_id: packet.thread_id,
+            timestamp: packet.timestamp,
+            cpu: packet.cpu,
+            rapl_measurement: client_packet.rapl_measurement.clone(),
+            pkg_overflow: client_packet.pkg_overflow,
+            parent_path: self
+                .region_stacks
+                .get(&(packet.process_id, packet.thread_id))
+                .map(|region_stack| {
+                    region_stack
+                        .iter()
+                        .map(|open_region| open_region.id.clone())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Returns the regions closed by the packet, or errors if the Stop does not match the open regions.
+    pub fn handle_packet(
+        &mut self,
+        client_packet: &ClientPacket,
+    ) -> Vec<Result<RegionResult, RegionError>> {
+        let packet = &client_packet.process_under_test_packet;
+        let thread_key = (packet.process_id, packet.thread_id);
+
+        match packet.operation {
+            // Marks do not open or close regions
+            ProcessUnderTestPacketOperation::Mark => Vec::new(),
+            ProcessUnderTestPacketOperation::Start => {
+                self.region_stacks
+                    .entry(thread_key)
+                    .or_default()
+                    .push(OpenRegion {
+                        id: packet.id.clone(),
+                        attributes: packet.attributes.clone(),
+                        timestamp: packet.timestamp,
+                        rapl_measurement: client_packet.rapl_measurement.clone(),
+                        pkg_overflow: client_packet.pkg_overflow,
+                        cpu_time: client_packet.cpu_time,
+                        cpu: packet.cpu,
+                        core_energy: client_packet.core_energy,
+                        cpu_migrations: client_packet.cpu_migrations,
+                        uncertainty: client_packet.uncertainty.clone(),
+                        children_energy: None,
+                        descendants: 0,
+                    });
+                Vec::new()
+            }
+            ProcessUnderTestPacketOperation::Stop => {
+                let Some(region_stack) = self.region_stacks.get_mut(&thread_key) else {
+                    return vec![Err(unmatched_stop(client_packet))];
+                };
+
+                // The innermost open region with the id is the one being stopped
+                let Some(position) = region_stack
+                    .iter()
+                    .rposition(|open_region| open_region.id == packet.id)
+                else {
+                    return vec![Err(unmatched_stop(client_packet))];
+                };
+
+                // Regions opened after it were never stopped
+                let mut results: Vec<Result<RegionResult, RegionError>> = region_stack
+                    .drain(position + 1..)
+                    .rev()
+                    .map(|open_region| {
+                        Err(RegionError::UnclosedRegion {
+                            id: open_region.id,
+                            process_id: packet.process_id,
+                            thread_id: packet.thread_id,
+                            start_timestamp: open_region.timestamp,
+                            closed_by: packet.id.clone(),
+                            stop_timestamp: packet.timestamp,
+                        })
+                    })
+                    .collect();
+
+                let open_region = region_stack.pop().unwrap();
+                let energy = region_energy(
+                    (&open_region.rapl_measurement, open_region.pkg_overflow),
+                    (&client_packet.rapl_measurement, client_packet.pkg_overflow),
+                    self.counter_wrap_joules,
+                );
+                let exclusive_energy = match &open_region.children_energy {
+                    Some(children_energy) => {
+                        energy.zip_with(children_energy, |energy, children| energy - children)
+                    }
+                    None => energy.clone(),
+                };
+
+                // Count the region towards its parent
+                if let Some(parent) = region_stack.last_mut() {
+                    parent.children_energy = Some(match &parent.children_energy {
+                        Some(children_energy) => {
+                            children_energy.zip_with(&energy, |children, energy| children + energy)
+                        }
+                        None => energy.clone(),
+                    });
+                    parent.descendants += open_region.descendants + 1;
+                }
+
+                let parent_path: Vec<String> = region_stack
+                    .iter()
+                    .map(|open_region| open_region.id.clone())
+                    .collect();
+                if region_stack.is_empty() {
+                    self.region_stacks.remove(&thread_key);
+                }
+
+                let duration_nanos = packet.timestamp.saturating_sub(open_region.timestamp);
+
+                // Calibration regions are only used to measure the overhead of the markers
+                if open_region.id == CALIBRATION_PAIR_ID
+                    || (open_region.id == CALIBRATION_REGION_ID && open_region.descendants == 0)
+                {
+                    return results;
+                }
+                if open_region.id == CALIBRATION_REGION_ID {
+                    let pairs = open_region.descendants;
+                    let overhead = InstrumentationOverhead {
+                        process_id: packet.process_id,
+                        pairs,
+                        duration_nanos: duration_nanos as f64 / pairs as f64,
+                        energy: energy.map(|joules| joules / pairs as f64),
+                    };
+                    println!("Calibrated instrumentation overhead: {:?}", overhead);
+                    self.overheads.insert(packet.process_id, overhead.clone());
+                    self.new_overheads.push(overhead);
+                    return results;
+                }
+
+                let cpu_time_share = open_region
+                    .cpu_time
+                    .zip(client_packet.cpu_time)
+                    .map(|(start, stop)| cpu_time_share(&start, &stop));
+                // A thread can move away and back, so its migrations are compared as well
+                let single_core = open_region
+                    .cpu
+                    .zip(packet.cpu)
+                    .zip(open_region.cpu_migrations.zip(client_packet.cpu_migrations))
+                    .map(
+                        |((start_cpu, stop_cpu), (start_migrations, stop_migrations))| {
+                            start_cpu == stop_cpu && start_migrations == stop_migrations
+                        },
+                    );
+                // The per-core counter is only meaningful if the thread stayed on the core
+                let core_energy = match single_core {
+                    Some(true) => open_region
+                        .core_energy
+                        .zip(client_packet.core_energy)
+                        .map(|(start, stop)| counter_delta(start, stop, self.counter_wrap_joules)),
+                    _ => None,
+                };
+                // The region contains the cost of its own markers and of every nested pair
+                let overhead_corrected_energy = self
+                    .overheads
+                    .get(&packet.process_id)
+                    .filter(|_| self.correct_overhead)
+                    .map(|overhead| {
+                        let pairs = (open_region.descendants + 1) as f64;
+                        energy.zip_with(&overhead.energy, |joules, overhead| {
+                            joules - overhead * pairs
+                        })
+                    });
+                // The work count is given on the Stop, regions without work are not normalized
+                let work_count = packet.work_count.filter(|&work_count| work_count > 0);
+                let joules_per_unit =
+                    work_count.map(|work_count| energy.map(|joules| joules / work_count as f64));
+                let units_per_joule = work_count
+                    .filter(|_| energy.pkg() > 0.0)
+                    .map(|work_count| work_count as f64 / energy.pkg());
+                let uncertainty = open_region
+                    .uncertainty
+                    .as_ref()
+                    .zip(client_packet.uncertainty.as_ref())
+                    .map(|(start, stop)| start.zip_with(stop, |start, stop| start + stop));
+                results.push(Ok(RegionResult {
+                    id: open_region.id,
+                    attributes: open_region.attributes,
+                    process_id: packet.process_id,
+                    thread_id: packet.thread_id,
+                    start_timestamp: open_region.timestamp,
+                    stop_timestamp: packet.timestamp,
+                    duration_nanos,
+                    energy_above_baseline: self
+                        .baseline
+                        .as_ref()
+                        .map(|baseline| energy_above_baseline(&energy, duration_nanos, baseline)),
+                    baseline: self.baseline.clone(),
+                    cpu_time_share,
+                    attributed_energy: cpu_time_share
+                        .map(|share| energy.map(|joules| joules * share)),
+                    start_cpu: open_region.cpu,
+                    stop_cpu: packet.cpu,
+                    single_core,
+                    core_energy,
+                    overhead_corrected_energy,
+                    work_count,
+                    joules_per_unit,
+                    units_per_joule,
+                    reliable: uncertainty.as_ref().is_some_and(|uncertainty| {
+                        is_reliable(&energy, uncertainty, self.reliability_threshold)
+                    }),
+                    uncertainty,
+                    energy,
+                    exclusive_energy,
+                    parent_path,
+                    synthetic_stop: client_packet.synthetic,
+                }));
+
+                results
+            }
+        }
+    }
+}
+
+fn unmatched_stop(client_packet: &ClientPacket) -> RegionError {
+    let packet = &client_packet.process_under_test_packet;
+    RegionError::UnmatchedStop {
+        id: packet.id.clone(),
+        process_id: packet.process_id,
+        thread_id: packet.thread_id,
+        timestamp: packet.timestamp,
+    }
+}
+
+/// Energy used between two cumulative measurements, each given with its package overflow count.
+///
+/// The package domain uses the overflow count from the sampler, the other domains are assumed
+/// to have wrapped at most once.
+pub fn region_energy(
+    (start, start_pkg_overflow): (&RaplMeasurementJoules, u32),
+    (stop, stop_pkg_overflow): (&RaplMeasurementJoules, u32),
+    counter_wrap_joules: f64,
+) -> RaplMeasurementJoules {
+    let delta = |start: f64, stop: f64| counter_delta(start, stop, counter_wrap_joules);
+    let pkg_delta = |start: f64, stop: f64| {
+        let overflows = stop_pkg_overflow.saturating_sub(start_pkg_overflow);
+        stop - start + overflows as f64 * counter_wrap_joules
+    };
+
+    match (start, stop) {
+        (RaplMeasurementJoules::Intel(start), RaplMeasurementJoules::Intel(stop)) => {
+            RaplMeasurementJoules::Intel(IntelRaplRegistersJoules {
+                pp0: delta(start.pp0, stop.pp0),
+                pp1: delta(start.pp1, stop.pp1),
+                pkg: pkg_delta(start.pkg, stop.pkg),
+                dram: delta(start.dram, stop.dram),
+            })
+        }
+        (RaplMeasurementJoules::AMD(start), RaplMeasurementJoules::AMD(stop)) => {
+            RaplMeasurementJoules::AMD(AmdRaplRegistersJoules {
+                core: delta(start.core, stop.core),
+                pkg: pkg_delta(start.pkg, stop.pkg),
+            })
+        }
+        _ => panic!("Start and stop RAPL measurements do not match"),
+    }
+}
+
+// Difference between two readings of a counter that wraps at most once in between
+fn counter_delta(start: f64, stop: f64, counter_wrap_joules: f64) -> f64 {
+    if stop >= start {
+        stop - start
+    } else {
+        stop + counter_wrap_joules - start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use thor_shared::ProcessUnderTestPacket;
+
+    const COUNTER_WRAP_JOULES: f64 = 100.0;
+
+    // Both domains of the AMD measurements read the given counter value
+    fn client_packet(
+        id: &str,
+        operation: ProcessUnderTestPacketOperation,
+        timestamp: u128,
+        joules: f64,
+    ) -> ClientPacket {
+        ClientPacket {
+            process_under_test_packet: ProcessUnderTestPacket {
+                id: id.to_string(),
+                process_id: 1,
+                thread_id: 1,
+                operation,
+                timestamp,
+                cpu: None,
+                work_count: None,
+                attributes: Vec::new(),
+                sequence: None,
+            },
+            rapl_measurement: measurement(joules),
+            pkg_overflow: 0,
+            synthetic: false,
+            cpu_time: None,
+            core_energy: None,
+            cpu_migrations: None,
+            uncertainty: Some(measurement(0.0)),
+        }
+    }
+
+    fn measurement(joules: f64) -> RaplMeasurementJoules {
+        RaplMeasurementJoules::AMD(AmdRaplRegistersJoules {
+            core: joules,
+            pkg: joules,
+        })
+    }
+
+    fn start(id: &str, timestamp: u128, joules: f64) -> ClientPacket {
+        client_packet(
+            id,
+            ProcessUnderTestPacketOperation::Start,
+            timestamp,
+            joules,
+        )
+    }
+
+    fn stop(id: &str, timestamp: u128, joules: f64) -> ClientPacket {
+        client_packet(id, ProcessUnderTestPacketOperation::Stop, timestamp, joules)
+    }
+
+    fn region_tracker() -> RegionTracker {
+        RegionTracker::new(COUNTER_WRAP_JOULES, 0.1)
+    }
+
+    fn region(results: Vec<Result<RegionResult, RegionError>>) -> RegionResult {
+        assert_eq!(results.len(), 1);
+        results.into_iter().next().unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_pairs_start_and_stop() {
+        let mut region_tracker = region_tracker();
+        assert!(region_tracker
+            .handle_packet(&start("a", 10, 1.0))
+            .is_empty());
+        let region = region(region_tracker.handle_packet(&stop("a", 30, 4.0)));
+
+        assert_eq!(region.id, "a");
+        assert_eq!(region.duration_nanos, 20);
+        assert_eq!(region.energy, measurement(3.0));
+        assert_eq!(region.exclusive_energy, measurement(3.0));
+        assert!(region.parent_path.is_empty());
+    }
+
+    #[test]
+    fn test_nested_regions_have_exclusive_energy() {
+        let mut region_tracker = region_tracker();
+        region_tracker.handle_packet(&start("outer", 0, 0.0));
+        region_tracker.handle_packet(&start("inner", 10, 1.0));
+        let inner = region(region_tracker.handle_packet(&stop("inner", 20, 3.0)));
+        let outer = region(region_tracker.handle_packet(&stop("outer", 30, 5.0)));
+
+        assert_eq!(inner.parent_path, ["outer"]);
+        assert_eq!(inner.energy, measurement(2.0));
+        assert_eq!(outer.energy, measurement(5.0));
+        assert_eq!(outer.exclusive_energy, measurement(3.0));
+    }
+
+    #[test]
+    fn test_recursive_regions_stop_the_innermost() {
+        let mut region_tracker = region_tracker();
+        region_tracker.handle_packet(&start("fib", 0, 0.0));
+        region_tracker.handle_packet(&start("fib", 10, 1.0));
+        let inner = region(region_tracker.handle_packet(&stop("fib", 20, 2.0)));
+        let outer = region(region_tracker.handle_packet(&stop("fib", 30, 4.0)));
+
+        assert_eq!(inner.start_timestamp, 10);
+        assert_eq!(inner.parent_path, ["fib"]);
+        assert_eq!(outer.start_timestamp, 0);
+        assert_eq!(outer.exclusive_energy, measurement(3.0));
+    }
+
+    #[test]
+    fn test_unclosed_and_unmatched_regions_are_errors() {
+        let mut region_tracker = region_tracker();
+        region_tracker.handle_packet(&start("outer", 0, 0.0));
+        region_tracker.handle_packet(&start("inner", 10, 1.0));
+        let results = region_tracker.handle_packet(&stop("outer", 30, 5.0));
+
+        assert_eq!(results.len(), 2);
+        assert!(matches!(
+            &results[0],
+            Err(RegionError::UnclosedRegion { id, closed_by, .. }) if id == "inner" && closed_by == "outer"
+        ));
+        assert!(results[1].is_ok());
+
+        let results = region_tracker.handle_packet(&stop("inner", 40, 6.0));
+        assert!(matches!(
+            &results[..],
+            [Err(RegionError::UnmatchedStop { id, .. })] if id == "inner"
+        ));
+    }
+
+    #[test]
+    fn test_calibration_measures_overhead() {
+        let mut region_tracker = region_tracker().correct_overhead(true);
+        region_tracker.handle_packet(&start(CALIBRATION_REGION_ID, 0, 0.0));
+        for pair in 0..4 {
+            let timestamp = 10 + pair * 10;
+            let joules = 1.0 + pair as f64;
+            assert!(region_tracker
+                .handle_packet(&start(CALIBRATION_PAIR_ID, timestamp, joules))
+                .is_empty());
+            assert!(region_tracker
+                .handle_packet(&stop(CALIBRATION_PAIR_ID, timestamp + 5, joules))
+                .is_empty());
+        }
+        // Calibration regions are not reported as regions
+        assert!(region_tracker
+            .handle_packet(&stop(CALIBRATION_REGION_ID, 80, 2.0))
+            .is_empty());
+
+        let overheads = region_tracker.take_overheads();
+        assert_eq!(overheads.len(), 1);
+        assert_eq!(overheads[0].pairs, 4);
+        assert_eq!(overheads[0].duration_nanos, 20.0);
+        assert_eq!(overheads[0].energy, measurement(0.5));
+        assert!(region_tracker.take_overheads().is_empty());
+
+        // The region and its nested pair each carry the overhead of one pair
+        region_tracker.handle_packet(&start("outer", 100, 10.0));
+        region_tracker.handle_packet(&start("inner", 110, 11.0));
+        region_tracker.handle_packet(&stop("inner", 120, 12.0));
+        let outer = region(region_tracker.handle_packet(&stop("outer", 130, 15.0)));
+        assert_eq!(outer.overhead_corrected_energy, Some(measurement(4.0)));
+    }
+
+    #[test]
+    fn test_regions_without_uncertainty_are_unreliable() {
+        let mut region_tracker = region_tracker();
+        region_tracker.handle_packet(&start("known", 0, 0.0));
+        assert!(region(region_tracker.handle_packet(&stop("known", 10, 1.0))).reliable);
+
+        let mut unknown = start("unknown", 20, 0.0);
+        unknown.uncertainty = None;
+        region_tracker.handle_packet(&unknown);
+        let region = region(region_tracker.handle_packet(&stop("unknown", 30, 1.0)));
+        assert!(region.uncertainty.is_none());
+        assert!(!region.reliable);
+    }
+
+    #[test]
+    fn test_migrated_regions_are_not_single_core() {
+        let on_cpu = |mut client_packet: ClientPacket, cpu_migrations: u64, core_energy: f64| {
+            client_packet.process_under_test_packet.cpu = Some(2);
+            client_packet.cpu_migrations = Some(cpu_migrations);
+            client_packet.core_energy = Some(core_energy);
+            client_packet
+        };
+
+        let mut region_tracker = region_tracker();
+        region_tracker.handle_packet(&on_cpu(start("stayed", 0, 0.0), 3, 1.0));
+        let stayed = region(region_tracker.handle_packet(&on_cpu(stop("stayed", 10, 1.0), 3, 1.5)));
+        assert_eq!(stayed.single_core, Some(true));
+        assert_eq!(stayed.core_energy, Some(0.5));
+
+        // Moved away and back to the same CPU
+        region_tracker.handle_packet(&on_cpu(start("moved", 20, 0.0), 3, 1.0));
+        let moved = region(region_tracker.handle_packet(&on_cpu(stop("moved", 30, 1.0), 5, 1.5)));
+        assert_eq!(moved.single_core, Some(false));
+        assert_eq!(moved.core_energy, None);
+
+        // Unknown without the migration count
+        region_tracker.handle_packet(&start("unknown", 40, 0.0));
+        let unknown = region(region_tracker.handle_packet(&stop("unknown", 50, 1.0)));
+        assert_eq!(unknown.single_core, None);
+    }
+
+    #[test]
+    fn test_counter_wraps() {
+        assert_eq!(counter_delta(90.0, 5.0, COUNTER_WRAP_JOULES), 15.0);
+        assert_eq!(counter_delta(5.0, 90.0, COUNTER_WRAP_JOULES), 85.0);
+
+        // The package domain counts its overflows instead
+        let energy = region_energy(
+            (&measurement(90.0), 1),
+            (&measurement(5.0), 3),
+            COUNTER_WRAP_JOULES,
+        );
+        assert_eq!(
+            energy,
+            RaplMeasurementJoules::AMD(AmdRaplRegistersJoules {
+                core: 15.0,
+                pkg: 115.0,
+            })
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use thor_shared::{ProcessNode, ProcessSession, SessionTree};
+
+/// Keeps track of the processes under test of a job, the processes they were started by and the
+/// markers lost or reordered on the way to the server.
+#[derive(Default)]
+pub struct SessionTracker {
+    // Parent process id per session and process id, kept from the latest connection of the process
+    parents: BTreeMap<String, BTreeMap<u32, u32>>,
+    // The process of each open connection, by session and process id
+    connections: HashMap<u64, Connection>,
+    // Markers of each process by session and process id, kept across jobs while the process is connected
+    senders: HashMap<(String, u32), Sender>,
+    lost_markers: HashMap<(String, u32), u64>,
+    reordered_markers: HashMap<(String, u32), u64>,
+}
+
+struct Connection {
+    sender: (String, u32),
+    // Set once the connection sent its first marker
+    started: bool,
+}
+
+#[derive(Default)]
+struct Sender {
+    next_sequence: u64,
+    // Ranges of sequence numbers skipped so far, by their first and past the last number
+    missing: BTreeMap<u64, u64>,
+}
+
+impl SessionTracker {
+    pub fn add(&mut self, connection: u64, process_session: ProcessSession) {
+        self.connections.insert(
+            connection,
+            Connection {
+                sender: (process_session.session.clone(), process_session.process_id),
+                started: false,
+            },
+        );
+        self.parents
+            .entry(process_session.session)
+            .or_default()
+            .insert(
+                process_session.process_id,
+                process_session.parent_process_id,
+            );
+    }
+
+    /// Forget a closed connection. The sequence numbers of its process continue on its next connection.
+    pub fn remove(&mut self, connection: u64) {
+        self.connections.remove(&connection);
+    }
+
+    /// Count the markers skipped by the sequence number of a marker received on a connection, returning
+    /// how many were lost, or None if the marker was received before and is to be dropped.
+    pub fn handle_sequence(
+        &mut self,
+        connection: u64,
+        process_id: u32,
+        sequence: u64,
+    ) -> Option<u64> {
+        let connection = self.connections.entry(connection).or_insert(Connection {
+            sender: (String::new(), process_id),
+            started: false,
+        });
+        let key = &connection.sender;
+        let sender = self.senders.entry(key.clone()).or_default();
+        // Processes number their markers from 0 over their connections, so the first marker of a
+        // connection numbered 0 comes from a new process reusing the process id
+        if !connection.started && sequence == 0 {
+            *sender = Sender::default();
+        }
+        connection.started = true;
+
+        if sequence < sender.next_sequence {
+            // Markers arriving after a later one fill the range they were counted as lost in
+            let (&start, &end) = sender
+                .missing
+                .range(..=sequence)
+                .next_back()
+                .filter(|&(_, &end)| sequence < end)?;
+            sender.missing.remove(&start);
+            if start < sequence {
+                sender.missing.insert(start, sequence);
+            }
+            if sequence + 1 < end {
+                sender.missing.insert(sequence + 1, end);
+            }
+            // The marker may have been counted as lost in an earlier job
+            if let Some(lost_markers) = self.lost_markers.get_mut(key) {
+                *lost_markers = lost_markers.saturating_sub(1);
+            }
+            *self.reordered_markers.entry(key.clone()).or_default() += 1;
+            return Some(0);
+        }
+
+        let lost = sequence - sender.next_sequence;
+        if lost > 0 {
+            sender.missing.insert(sender.next_sequence, sequence);
+            *self.lost_markers.entry(key.clone()).or_default() += lost;
+        }
+        sender.next_sequence = sequence + 1;
+        Some(lost)
+    }
+
+    pub fn clear(&mut self) {
+        self.parents.clear();
+        self.lost_markers.clear();
+        self.reordered_markers.clear();
+        // The markers of processes that disconnected are no longer tracked
+        let connections = &self.connections;
+        self.senders.retain(|key, _| {
+            connections
+                .values()
+                .any(|connection| connection.sender == *key)
+        });
+    }
+
+    /// The processes of every session, starting from those whose parent did not connect.
+    pub fn trees(&self) -> Vec<SessionTree> {
+        self.parents
+            .iter()
+            .map(|(session, parents)| {
+                let mut children: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+                for (&process_id, &parent_process_id) in parents {
+                    children
+                        .entry(parent_process_id)
+                        .or_default()
+                        .push(process_id);
+                }
+
+                let mut visited = BTreeSet::new();
+                let mut processes: Vec<ProcessNode> = parents
+                    .iter()
+                    .filter(|(_, parent_process_id)| !parents.contains_key(parent_process_id))
+                    .map(|(&process_id, _)| process_node(process_id, &children, &mut visited))
+                    .collect();
+                // Reused process ids can make a cycle without a root, which is listed from any of its processes
+                for &process_id in parents.keys() {
+                    if !visited.contains(&process_id) {
+                        processes.push(process_node(process_id, &children, &mut visited));
+                    }
+                }
+
+                let count = |markers: &HashMap<(String, u32), u64>| -> u64 {
+                    parents
+                        .keys()
+                        .filter_map(|&process_id| markers.get(&(session.clone(), process_id)))
+                        .sum()
+                };
+                SessionTree {
+                    session: session.clone(),
+                    processes,
+                    lost_markers: count(&self.lost_markers),
+                    reordered_markers: count(&self.reordered_markers),
+                }
+            })
+            .collect()
+    }
+}
+
+fn process_node(
+    process_id: u32,
+    children: &BTreeMap<u32, Vec<u32>>,
+    visited: &mut BTreeSet<u32>,
+) -> ProcessNode {
+    visited.insert(process_id);
+    let mut child_nodes = Vec::new();
+    for &child_id in children.get(&process_id).into_iter().flatten() {
+        if !visited.contains(&child_id) {
+            child_nodes.push(process_node(child_id, children, visited));
+        }
+    }
+    ProcessNode {
+        process_id,
+        children: child_nodes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(tracker: &mut SessionTracker, connection: u64, session: &str, process_id: u32) {
+        tracker.add(
+            connection,
+            ProcessSession {
+                session: session.to_string(),
+                process_id,
+                parent_process_id: 1,
+            },
+        );
+    }
+
+    // The lost and reordered markers of the only session
+    fn counts(tracker: &SessionTracker) -> (u64, u64) {
+        let trees = tracker.trees();
+        assert_eq!(trees.len(), 1);
+        (trees[0].lost_markers, trees[0].reordered_markers)
+    }
+
+    #[test]
+    fn test_gaps_are_counted_as_lost() {
+        let mut tracker = SessionTracker::default();
+        connect(&mut tracker, 1, "session", 10);
+
+        assert_eq!(tracker.handle_sequence(1, 10, 0), Some(0));
+        assert_eq!(tracker.handle_sequence(1, 10, 1), Some(0));
+        assert_eq!(tracker.handle_sequence(1, 10, 4), Some(2));
+        assert_eq!(tracker.handle_sequence(1, 10, 5), Some(0));
+        assert_eq!(counts(&tracker), (2, 0));
+
+        // Arriving after a later marker, it is no longer lost
+        assert_eq!(tracker.handle_sequence(1, 10, 3), Some(0));
+        assert_eq!(counts(&tracker), (1, 1));
+    }
+
+    #[test]
+    fn test_duplicates_are_dropped() {
+        let mut tracker = SessionTracker::default();
+        connect(&mut tracker, 1, "session", 10);
+
+        for sequence in [0, 1, 3] {
+            tracker.handle_sequence(1, 10, sequence);
+        }
+        assert_eq!(tracker.handle_sequence(1, 10, 1), None);
+        assert_eq!(tracker.handle_sequence(1, 10, 3), None);
+        assert_eq!(tracker.handle_sequence(1, 10, 2), Some(0));
+        assert_eq!(tracker.handle_sequence(1, 10, 2), None);
+        assert_eq!(counts(&tracker), (0, 1));
+
+        // Sent again over a new connection after the first was lost
+        tracker.remove(1);
+        connect(&mut tracker, 2, "session", 10);
+        assert_eq!(tracker.handle_sequence(2, 10, 3), None);
+        assert_eq!(tracker.handle_sequence(2, 10, 4), Some(0));
+        assert_eq!(counts(&tracker), (0, 1));
+    }
+
+    #[test]
+    fn test_sequence_0_arriving_late() {
+        let mut tracker = SessionTracker::default();
+        connect(&mut tracker, 1, "session", 10);
+
+        assert_eq!(tracker.handle_sequence(1, 10, 1), Some(1));
+        assert_eq!(tracker.handle_sequence(1, 10, 2), Some(0));
+        assert_eq!(tracker.handle_sequence(1, 10, 0), Some(0));
+        assert_eq!(counts(&tracker), (0, 1));
+
+        // Received again, it does not start the numbers over
+        assert_eq!(tracker.handle_sequence(1, 10, 0), None);
+        assert_eq!(tracker.handle_sequence(1, 10, 3), Some(0));
+        assert_eq!(counts(&tracker), (0, 1));
+    }
+
+    #[test]
+    fn test_sequences_continue_over_the_connections_of_a_process() {
+        let mut tracker = SessionTracker::default();
+        connect(&mut tracker, 1, "session", 10);
+        tracker.handle_sequence(1, 10, 0);
+        tracker.handle_sequence(1, 10, 1);
+        tracker.remove(1);
+
+        // The markers dropped while the process was not connected are lost
+        connect(&mut tracker, 2, "session", 10);
+        assert_eq!(tracker.handle_sequence(2, 10, 4), Some(2));
+        assert_eq!(counts(&tracker), (2, 0));
+    }
+
+    #[test]
+    fn test_reused_process_id_starts_over() {
+        let mut tracker = SessionTracker::default();
+        connect(&mut tracker, 1, "session", 10);
+        for sequence in 0..5 {
+            tracker.handle_sequence(1, 10, sequence);
+        }
+        tracker.remove(1);
+
+        connect(&mut tracker, 2, "session", 10);
+        assert_eq!(tracker.handle_sequence(2, 10, 0), Some(0));
+        assert_eq!(tracker.handle_sequence(2, 10, 1), Some(0));
+        assert_eq!(counts(&tracker), (0, 0));
+    }
+
+    #[test]
+    fn test_same_process_id_in_other_sessions() {
+        let mut tracker = SessionTracker::default();
+        connect(&mut tracker, 1, "first", 10);
+        connect(&mut tracker, 2, "second", 10);
+
+        for sequence in 0..3 {
+            assert_eq!(tracker.handle_sequence(1, 10, sequence), Some(0));
+            assert_eq!(tracker.handle_sequence(2, 10, sequence), Some(0));
+        }
+
+        // Counted in the session of the connection only
+        assert_eq!(tracker.handle_sequence(2, 10, 5), Some(2));
+        let lost_markers: Vec<(String, u64)> = tracker
+            .trees()
+            .into_iter()
+            .map(|tree| (tree.session, tree.lost_markers))
+            .collect();
+        assert_eq!(
+            lost_markers,
+            [("first".to_string(), 0), ("second".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn test_disconnected_processes_are_forgotten_by_the_next_job() {
+        let mut tracker = SessionTracker::default();
+        connect(&mut tracker, 1, "session", 10);
+        connect(&mut tracker, 2, "session", 11);
+        tracker.handle_sequence(1, 10, 0);
+        tracker.handle_sequence(2, 11, 0);
+        tracker.handle_sequence(2, 11, 2);
+        tracker.remove(2);
+
+        tracker.clear();
+        assert_eq!(tracker.senders.len(), 1);
+
+        // The connected process continues its numbers
+        assert_eq!(tracker.handle_sequence(1, 10, 1), Some(0));
+        assert!(tracker.lost_markers.is_empty());
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{component_def::Measurement, regions::region_energy};
+use thor_lib::RaplMeasurementJoules;
+
+/// Mean power in watts between each pair of consecutive timestamps, None if a timestamp has no sample.
+pub fn window_watts<M: Measurement<(RaplMeasurementJoules, u32)>>(
+    measurement: &mut M,
+    timestamps: &[u128],
+    counter_wrap_joules: f64,
+) -> Option<Vec<RaplMeasurementJoules>> {
+    let measurements = timestamps
+        .iter()
+        .map(|timestamp| measurement.try_get_measurement(*timestamp))
+        .collect::<Option<Vec<_>>>()?;
+
+    let watts = measurements
+        .windows(2)
+        .zip(timestamps.windows(2))
+        .map(|(window, window_timestamps)| {
+            let seconds = (window_timestamps[1] - window_timestamps[0]) as f64 / 1_000_000_000.0;
+            region_energy(
+                (&window[0].0, window[0].1),
+                (&window[1].0, window[1].1),
+                counter_wrap_joules,
+            )
+            .map(|joules| joules / seconds)
+        })
+        .collect();
+    Some(watts)
+}
+
+/// Follows the power of the machine in steps of a fixed resolution, continuing where the last call stopped.
+pub struct TimelineCursor {
+    resolution_nanos: u128,
+    next_timestamp: Option<u128>,
+}
+
+impl TimelineCursor {
+    pub fn new(resolution_nanos: u128) -> TimelineCursor {
+        TimelineCursor {
+            resolution_nanos: resolution_nanos.max(1),
+            next_timestamp: None,
+        }
+    }
+
+    pub fn resolution_nanos(&self) -> u128 {
+        self.resolution_nanos
+    }
+
+    /// The start timestamp and power of every whole step up to the timestamp, starting from it on the first call.
+    pub fn advance<M: Measurement<(RaplMeasurementJoules, u32)>>(
+        &mut self,
+        measurement: &mut M,
+        timestamp: u128,
+        counter_wrap_joules: f64,
+    ) -> Option<(u128, Vec<RaplMeasurementJoules>)> {
+        let start_timestamp = *self.next_timestamp.get_or_insert(timestamp);
+        let steps = timestamp.saturating_sub(start_timestamp) / self.resolution_nanos;
+        if steps == 0 {
+            return None;
+        }
+
+        let timestamps: Vec<u128> = (0..=steps)
+            .map(|step| start_timestamp + step * self.resolution_nanos)
+            .collect();
+        self.next_timestamp = Some(timestamps[timestamps.len() - 1]);
+
+        // Samples missing from a slow sampler, or a cursor left behind the retained samples, start over
+        let Some(watts) = window_watts(measurement, &timestamps, counter_wrap_joules) else {
+            self.reset();
+            return None;
+        };
+        Some((start_timestamp, watts))
+    }
+
+    /// Stop following the power, the next call to advance starts over.
+    pub fn reset(&mut self) {
+        self.next_timestamp = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::Range;
+    use thor_lib::AmdRaplRegistersJoules;
+    use thor_shared::EnergyQueryResult;
+
+    // One watt in every domain, without samples in the missing range
+    struct Samples {
+        missing: Range<u128>,
+    }
+
+    impl Measurement<(RaplMeasurementJoules, u32)> for Samples {
+        fn try_get_measurement(&mut self, timestamp: u128) -> Option<(RaplMeasurementJoules, u32)> {
+            if self.missing.contains(&timestamp) {
+                return None;
+            }
+            let joules = timestamp as f64 / 1_000_000_000.0;
+            Some((
+                RaplMeasurementJoules::AMD(AmdRaplRegistersJoules {
+                    core: joules,
+                    pkg: joules,
+                }),
+                0,
+            ))
+        }
+
+        fn get_sample_spacing(&mut self, _timestamp: u128) -> Option<u128> {
+            None
+        }
+
+        fn query_energy(
+            &mut self,
+            _start_timestamp: u128,
+            _stop_timestamp: u128,
+        ) -> Result<EnergyQueryResult, String> {
+            Err("Not sampled".to_string())
+        }
+    }
+
+    const STEP: u128 = 1_000_000;
+    const WRAP: f64 = 1e9;
+
+    #[test]
+    fn test_advance_follows_the_power() {
+        let mut samples = Samples { missing: 0..0 };
+        let mut cursor = TimelineCursor::new(STEP);
+
+        assert!(cursor.advance(&mut samples, 10 * STEP, WRAP).is_none());
+        let (start_timestamp, watts) = cursor.advance(&mut samples, 13 * STEP, WRAP).unwrap();
+        assert_eq!(start_timestamp, 10 * STEP);
+        assert_eq!(watts.len(), 3);
+        assert!(watts.iter().all(|watts| watts
+            .domains()
+            .iter()
+            .all(|(_, watts)| (watts - 1.0).abs() < 1e-6)));
+
+        // Continues where the last call stopped
+        let (start_timestamp, _) = cursor.advance(&mut samples, 15 * STEP, WRAP).unwrap();
+        assert_eq!(start_timestamp, 13 * STEP);
+    }
+
+    #[test]
+    fn test_advance_starts_over_when_samples_are_missing() {
+        let mut samples = Samples {
+            missing: 11 * STEP..12 * STEP,
+        };
+        let mut cursor = TimelineCursor::new(STEP);
+
+        assert!(cursor.advance(&mut samples, 10 * STEP, WRAP).is_none());
+        assert!(cursor.advance(&mut samples, 13 * STEP, WRAP).is_none());
+
+        // Starts from the timestamp of the next call, after the missing samples
+        assert!(cursor.advance(&mut samples, 14 * STEP, WRAP).is_none());
+        let (start_timestamp, watts) = cursor.advance(&mut samples, 16 * STEP, WRAP).unwrap();
+        assert_eq!(start_timestamp, 14 * STEP);
+        assert_eq!(watts.len(), 2);
+    }
+}
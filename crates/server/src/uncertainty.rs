@@ -0,0 +1,27 @@
+use thor_lib::RaplMeasurementJoules;
+
+// The RAPL counters are updated by the CPU about once every millisecond
+pub const RAPL_UPDATE_INTERVAL_NANOS: u128 = 1_000_000;
+
+// The power around a marker is measured over this window before it
+pub const POWER_WINDOW_NANOS: u128 = 10_000_000;
+
+/// How far the counters may be off at a marker: the energy used while the counters could lag
+/// behind, i.e. the spacing of the samples around it plus one counter update, and one energy unit.
+pub fn marker_uncertainty(
+    watts: &RaplMeasurementJoules,
+    sample_spacing_nanos: u128,
+    energy_unit_joules: f64,
+) -> RaplMeasurementJoules {
+    let seconds = (sample_spacing_nanos + RAPL_UPDATE_INTERVAL_NANOS) as f64 / 1_000_000_000.0;
+    watts.map(|watts| watts.max(0.0) * seconds + energy_unit_joules)
+}
+
+/// A region is reliable if its package energy uncertainty is at most the threshold share of its package energy.
+pub fn is_reliable(
+    energy: &RaplMeasurementJoules,
+    uncertainty: &RaplMeasurementJoules,
+    reliability_threshold: f64,
+) -> bool {
+    energy.pkg() > 0.0 && uncertainty.pkg() <= energy.pkg() * reliability_threshold
+}
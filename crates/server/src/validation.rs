@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use thor_shared::{
+    MarkerInfo, ProcessUnderTestPacket, ProcessUnderTestPacketOperation, ValidationReport,
+};
+
+/// Keeps track of the regions opened over a single process under test connection.
+#[derive(Default)]
+pub struct ConnectionValidator {
+    process_id: Option<u32>,
+    packets: u64,
+    // Stack of open region ids and their start timestamps per thread
+    open_regions: HashMap<usize, Vec<(String, u128)>>,
+    orphaned_stops: Vec<MarkerInfo>,
+    duplicate_starts: Vec<MarkerInfo>,
+    synthetic_stops: Vec<MarkerInfo>,
+    dropped_markers: u64,
+}
+
+impl ConnectionValidator {
+    pub fn handle_packet(&mut self, packet: &ProcessUnderTestPacket) {
+        self.process_id.get_or_insert(packet.process_id);
+        self.packets += 1;
+
+        let region_stack = self.open_regions.entry(packet.thread_id).or_default();
+
+        match packet.operation {
+            ProcessUnderTestPacketOperation::Start => {
+                // Recursive code opens an id that is already open, only the same Start twice is a duplicate
+                if region_stack.last() == Some(&(packet.id.clone(), packet.timestamp)) {
+                    self.duplicate_starts.push(marker_info(packet));
+                } else {
+                    region_stack.push((packet.id.clone(), packet.timestamp));
+                }
+            }
+            ProcessUnderTestPacketOperation::Stop => {
+                match region_stack.iter().rposition(|(id, _)| *id == packet.id) {
+                    // Regions opened after the stopped one are closed along with it
+                    Some(position) => region_stack.truncate(position),
+                    None => self.orphaned_stops.push(marker_info(packet)),
+                }
+            }
+            ProcessUnderTestPacketOperation::Mark => {}
+        }
+    }
+
+    /// Record the number of markers the process dropped before they reached the server.
+    pub fn set_dropped_markers(&mut self, dropped_markers: u64) {
+        self.dropped_markers = dropped_markers;
+    }
+
+    /// Stop packets for every region left open, innermost first, to be used when the connection drops.
+    pub fn synthetic_stops(&mut self, timestamp: u128) -> Vec<ProcessUnderTestPacket> {
+        let Some(process_id) = self.process_id else {
+            return Vec::new();
+        };
+
+        let mut stops = Vec::new();
+        for (thread_id, region_stack) in self.open_regions.drain() {
+            for (id, _) in region_stack.into_iter().rev() {
+                self.synthetic_stops.push(MarkerInfo {
+                    id: id.clone(),
+                    thread_id,
+                    timestamp,
+                });
+                stops.push(ProcessUnderTestPacket {
+                    id,
+                    process_id,
+                    thread_id,
+                    operation: ProcessUnderTestPacketOperation::Stop,
+                    timestamp,
+                    cpu: None,
+                    work_count: None,
+                    attributes: Vec::new(),
+                    sequence: None,
+                });
+            }
+        }
+        stops
+    }
+
+    /// The report for the connection, if any packets were received over it.
+    pub fn into_report(self) -> Option<ValidationReport> {
+        Some(ValidationReport {
+            process_id: self.process_id?,
+            packets: self.packets,
+            orphaned_stops: self.orphaned_stops,
+            duplicate_starts: self.duplicate_starts,
+            synthetic_stops: self.synthetic_stops,
+            dropped_markers: self.dropped_markers,
+        })
+    }
+}
+
+fn marker_info(packet: &ProcessUnderTestPacket) -> MarkerInfo {
+    MarkerInfo {
+        id: packet.id.clone(),
+        thread_id: packet.thread_id,
+        timestamp: packet.timestamp,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(
+        id: &str,
+        thread_id: usize,
+        operation: ProcessUnderTestPacketOperation,
+        timestamp: u128,
+    ) -> ProcessUnderTestPacket {
+        ProcessUnderTestPacket {
+            id: id.to_string(),
+            process_id: 1,
+            thread_id,
+            operation,
+            timestamp,
+            cpu: None,
+            work_count: None,
+            attributes: Vec::new(),
+            sequence: None,
+        }
+    }
+
+    fn start(id: &str, timestamp: u128) -> ProcessUnderTestPacket {
+        packet(id, 1, ProcessUnderTestPacketOperation::Start, timestamp)
+    }
+
+    fn stop(id: &str, timestamp: u128) -> ProcessUnderTestPacket {
+        packet(id, 1, ProcessUnderTestPacketOperation::Stop, timestamp)
+    }
+
+    fn validate(packets: &[ProcessUnderTestPacket]) -> ConnectionValidator {
+        let mut validator = ConnectionValidator::default();
+        for packet in packets {
+            validator.handle_packet(packet);
+        }
+        validator
+    }
+
+    #[test]
+    fn test_no_report_without_packets() {
+        assert!(ConnectionValidator::default().into_report().is_none());
+    }
+
+    #[test]
+    fn test_recursive_regions_are_clean() {
+        let mut validator = validate(&[
+            start("fib", 0),
+            start("fib", 1),
+            start("fib", 2),
+            stop("fib", 3),
+            stop("fib", 4),
+            stop("fib", 5),
+        ]);
+        assert!(validator.synthetic_stops(6).is_empty());
+        let report = validator.into_report().unwrap();
+        assert_eq!(report.packets, 6);
+        assert!(report.is_clean());
+    }
+
+    #[test]
+    fn test_orphaned_stops_and_duplicate_starts() {
+        let report = validate(&[stop("a", 0), start("b", 1), start("b", 1), stop("b", 2)])
+            .into_report()
+            .unwrap();
+        assert_eq!(report.orphaned_stops.len(), 1);
+        assert_eq!(report.orphaned_stops[0].id, "a");
+        assert_eq!(report.duplicate_starts.len(), 1);
+        assert_eq!(report.duplicate_starts[0].timestamp, 1);
+        assert!(report.synthetic_stops.is_empty());
+    }
+
+    #[test]
+    fn test_stopping_an_outer_region_closes_the_nested_ones() {
+        let mut validator = validate(&[start("outer", 0), start("inner", 1), stop("outer", 2)]);
+        assert!(validator.synthetic_stops(3).is_empty());
+        // The nested region is closed as well, so its Stop is orphaned
+        validator.handle_packet(&stop("inner", 4));
+        assert_eq!(validator.into_report().unwrap().orphaned_stops.len(), 1);
+    }
+
+    #[test]
+    fn test_synthetic_stops_close_open_regions_innermost_first() {
+        let mut validator = validate(&[
+            start("outer", 0),
+            start("inner", 1),
+            packet("other", 2, ProcessUnderTestPacketOperation::Start, 2),
+        ]);
+        let mut stops = validator.synthetic_stops(10);
+        stops.sort_by_key(|stop| stop.thread_id);
+
+        let stopped: Vec<_> = stops
+            .iter()
+            .map(|stop| (stop.id.as_str(), stop.thread_id, stop.timestamp))
+            .collect();
+        assert_eq!(
+            stopped,
+            [("inner", 1, 10), ("outer", 1, 10), ("other", 2, 10)]
+        );
+        assert!(stops
+            .iter()
+            .all(|stop| stop.operation == ProcessUnderTestPacketOperation::Stop));
+
+        let report = validator.into_report().unwrap();
+        assert_eq!(report.synthetic_stops.len(), 3);
+        assert!(!report.is_clean());
+    }
+}
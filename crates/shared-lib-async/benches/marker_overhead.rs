@@ -0,0 +1,77 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::{
+    env,
+    io::{self, Read, Write},
+    net::TcpListener,
+    thread,
+    time::{Duration, Instant},
+};
+use thor_shared::protocol::encode_handshake;
+
+// Threads marking regions at the same time, which the sync library serializes on its connection
+const CONTENDING_THREADS: u64 = 4;
+
+/// Accepts process under test connections and discards the markers, standing in for the server.
+fn start_sink() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    env::set_var("THOR_TRANSPORT", "tcp");
+    env::set_var("THOR_ADDR", listener.local_addr().unwrap().to_string());
+
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let mut handshake = [0; 3];
+                stream.read_exact(&mut handshake)?;
+                stream.write_all(&encode_handshake())?;
+                io::copy(&mut stream, &mut io::sink())
+            });
+        }
+    });
+}
+
+fn contended(pair: fn(&str)) -> impl FnMut(u64) -> Duration {
+    move |iterations| {
+        let start = Instant::now();
+        let threads: Vec<_> = (0..CONTENDING_THREADS)
+            .map(|_| {
+                thread::spawn(move || {
+                    for _ in 0..iterations {
+                        pair("contended");
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        start.elapsed()
+    }
+}
+
+// The Rust functions are called, as the C functions of both libraries have the same symbols
+fn sync_pair(id: &str) {
+    shared_lib_sync::library::start_rapl(id);
+    shared_lib_sync::library::stop_rapl(id);
+}
+
+fn async_pair(id: &str) {
+    shared_lib_async::library::start_rapl(id);
+    shared_lib_async::library::stop_rapl(id);
+}
+
+fn marker_overhead(c: &mut Criterion) {
+    start_sink();
+
+    let mut group = c.benchmark_group("start_stop");
+    group.bench_function("sync", |b| b.iter(|| sync_pair("region")));
+    group.bench_function("async", |b| b.iter(|| async_pair("region")));
+    group.finish();
+
+    let mut group = c.benchmark_group("start_stop_contended");
+    group.bench_function("sync", |b| b.iter_custom(contended(sync_pair)));
+    group.bench_function("async", |b| b.iter_custom(contended(async_pair)));
+    group.finish();
+}
+
+criterion_group!(benches, marker_overhead);
+criterion_main!(benches);
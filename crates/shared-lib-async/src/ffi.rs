@@ -0,0 +1,146 @@
+// The C functions of the library, prefixed with `thor_async_` so they do not clash with those of
+// shared-lib-sync when both are linked into one program.
+
+use crate::library;
+use std::{
+    ffi::{c_char, CStr},
+    ptr,
+};
+
+/// # Safety
+///
+/// This function is unsafe because it dereferences the `id` pointer.
+#[no_mangle]
+pub unsafe extern "C" fn thor_async_start_rapl(id: *const c_char) {
+    let id_cstr = CStr::from_ptr(id);
+    let id_string = String::from_utf8_lossy(id_cstr.to_bytes()).to_string();
+
+    library::start_rapl(id_string);
+}
+
+/// # Safety
+///
+/// This function is unsafe because it dereferences the `id` pointer, and the `keys` and `values`
+/// pointers which must point to `n` strings each.
+#[no_mangle]
+pub unsafe extern "C" fn thor_async_start_rapl_with_attrs(
+    id: *const c_char,
+    keys: *const *const c_char,
+    values: *const *const c_char,
+    n: usize,
+) {
+    let id_cstr = CStr::from_ptr(id);
+    let id_string = String::from_utf8_lossy(id_cstr.to_bytes()).to_string();
+
+    let mut keys_strings = Vec::with_capacity(n);
+    let mut values_strings = Vec::with_capacity(n);
+    for i in 0..n {
+        keys_strings.push(String::from_utf8_lossy(
+            CStr::from_ptr(*keys.add(i)).to_bytes(),
+        ));
+        values_strings.push(String::from_utf8_lossy(
+            CStr::from_ptr(*values.add(i)).to_bytes(),
+        ));
+    }
+    let attributes: Vec<(&str, &str)> = keys_strings
+        .iter()
+        .zip(&values_strings)
+        .map(|(key, value)| (key.as_ref(), value.as_ref()))
+        .collect();
+
+    library::start_rapl_with_attrs(id_string, &attributes);
+}
+
+/// # Safety
+///
+/// This function is unsafe because it dereferences the `id` pointer.
+#[no_mangle]
+pub unsafe extern "C" fn thor_async_stop_rapl(id: *const c_char) {
+    let id_cstr = CStr::from_ptr(id);
+    let id_string = String::from_utf8_lossy(id_cstr.to_bytes()).to_string();
+
+    library::stop_rapl(id_string);
+}
+
+/// # Safety
+///
+/// This function is unsafe because it dereferences the `id` pointer.
+#[no_mangle]
+pub unsafe extern "C" fn thor_async_stop_rapl_with_count(id: *const c_char, count: u64) {
+    let id_cstr = CStr::from_ptr(id);
+    let id_string = String::from_utf8_lossy(id_cstr.to_bytes()).to_string();
+
+    library::stop_rapl_with_count(id_string, count);
+}
+
+/// # Safety
+///
+/// This function is unsafe because it dereferences the `id` pointer.
+#[no_mangle]
+pub unsafe extern "C" fn thor_async_mark_rapl(id: *const c_char) {
+    let id_cstr = CStr::from_ptr(id);
+    let id_string = String::from_utf8_lossy(id_cstr.to_bytes()).to_string();
+
+    library::mark_rapl(id_string);
+}
+
+/// Register a region id, returning a handle that the `_handle` markers can use instead of the id.
+///
+/// # Safety
+///
+/// This function is unsafe because it dereferences the `id` pointer.
+#[no_mangle]
+pub unsafe extern "C" fn thor_async_register_region(id: *const c_char) -> u32 {
+    let id_cstr = CStr::from_ptr(id);
+    let id_string = String::from_utf8_lossy(id_cstr.to_bytes());
+
+    library::register_region(id_string)
+}
+
+#[no_mangle]
+pub extern "C" fn thor_async_start_rapl_handle(handle: u32) {
+    library::start_rapl_handle(handle);
+}
+
+#[no_mangle]
+pub extern "C" fn thor_async_stop_rapl_handle(handle: u32) {
+    library::stop_rapl_handle(handle);
+}
+
+#[no_mangle]
+pub extern "C" fn thor_async_mark_rapl_handle(handle: u32) {
+    library::mark_rapl_handle(handle);
+}
+
+/// Measure the cost of the markers with the given number of empty Start/Stop pairs.
+/// Returns the time one pair takes in nanoseconds.
+#[no_mangle]
+pub extern "C" fn thor_async_calibrate_rapl(pairs: u32) -> u64 {
+    library::calibrate_overhead(pairs).as_nanos() as u64
+}
+
+/// Send the queued markers to the server now. Called automatically when the process exits, except on Windows.
+#[no_mangle]
+pub extern "C" fn thor_async_flush_rapl() {
+    library::flush();
+}
+
+/// Copy the last error of the library into `buffer` as a NUL-terminated string, truncated to
+/// `length` bytes. Returns the length of the whole message, or 0 if there was no error.
+///
+/// # Safety
+///
+/// This function is unsafe because it writes to `buffer`, which must hold `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn thor_async_last_error(buffer: *mut c_char, length: usize) -> usize {
+    let Some(message) = library::last_error() else {
+        return 0;
+    };
+
+    if !buffer.is_null() && length > 0 {
+        let copied = message.len().min(length - 1);
+        ptr::copy_nonoverlapping(message.as_ptr() as *const c_char, buffer, copied);
+        *buffer.add(copied) = 0;
+    }
+    message.len()
+}
@@ -0,0 +1,261 @@
+use crossbeam::queue::SegQueue;
+use std::{
+    env, process, ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, Ordering},
+        Once,
+    },
+    thread,
+    time::Duration,
+};
+use thor_shared::{
+    client::{
+        self, buffer_capacity, run_pending_calibration, session_message, LibraryState,
+        MarkerLibrary,
+    },
+    protocol, MarkerPacket, ProcessUnderTestMessage,
+};
+
+static INIT: Once = Once::new();
+
+// Cleared in a forked child, which starts a background thread of its own
+static WORKER_STARTED: AtomicBool = AtomicBool::new(false);
+
+// Markers and region registrations waiting for the background thread, pushed without taking a
+// lock. A forked child replaces it, as the threads of the parent may have been pushing to it when it forked
+static QUEUE: AtomicPtr<SegQueue<ProcessUnderTestMessage>> = AtomicPtr::new(ptr::null_mut());
+
+static STATE: LibraryState = LibraryState::new();
+
+// Looked up once, as it is a system call
+static PROCESS_ID: AtomicU32 = AtomicU32::new(0);
+
+static BATCH_INTERVAL_MICROS: AtomicU64 = AtomicU64::new(DEFAULT_BATCH_INTERVAL_MICROS);
+
+// How often the background thread sends the queued markers
+const BATCH_INTERVAL_VAR: &str = "THOR_BATCH_MICROS";
+const DEFAULT_BATCH_INTERVAL_MICROS: u64 = 1000;
+
+// Queues the markers for a background thread, which sends them in batches
+struct AsyncLibrary;
+
+impl MarkerLibrary for AsyncLibrary {
+    fn state() -> &'static LibraryState {
+        &STATE
+    }
+
+    fn init() {
+        start_worker();
+    }
+
+    fn send(marker: MarkerPacket) {
+        queue().push(ProcessUnderTestMessage::Marker(marker));
+    }
+
+    // Sent by the background thread too, so registering a region does not wait for the server
+    fn send_registration(handle: u32, id: &str) {
+        queue().push(ProcessUnderTestMessage::RegisterRegion {
+            handle,
+            id: id.to_string(),
+        });
+    }
+
+    fn process_id() -> u32 {
+        PROCESS_ID.load(Ordering::Relaxed)
+    }
+}
+
+pub fn start_rapl(id: impl AsRef<str>) {
+    client::start_rapl::<AsyncLibrary>(id);
+}
+
+/// Start a region with key/value attributes, which are reported with its results.
+pub fn start_rapl_with_attrs(id: impl AsRef<str>, attributes: &[(&str, &str)]) {
+    client::start_rapl_with_attrs::<AsyncLibrary>(id, attributes);
+}
+
+pub fn stop_rapl(id: impl AsRef<str>) {
+    client::stop_rapl::<AsyncLibrary>(id);
+}
+
+/// Stop a region that processed the given number of work units, so the server can report the energy per unit.
+pub fn stop_rapl_with_count(id: impl AsRef<str>, work_count: u64) {
+    client::stop_rapl_with_count::<AsyncLibrary>(id, work_count);
+}
+
+/// Mark a point in time, such as "cache warmed", which is reported with the energy counters at that instant.
+pub fn mark_rapl(id: impl AsRef<str>) {
+    client::mark_rapl::<AsyncLibrary>(id);
+}
+
+/// The handle of a region id, which the markers send instead of the id. The id is sent to the server once per connection.
+pub fn register_region(id: impl AsRef<str>) -> u32 {
+    client::register_region::<AsyncLibrary>(id)
+}
+
+pub fn start_rapl_handle(handle: u32) {
+    client::start_rapl_handle::<AsyncLibrary>(handle);
+}
+
+pub fn stop_rapl_handle(handle: u32) {
+    client::stop_rapl_handle::<AsyncLibrary>(handle);
+}
+
+pub fn mark_rapl_handle(handle: u32) {
+    client::mark_rapl_handle::<AsyncLibrary>(handle);
+}
+
+/// Measure the cost of the markers by sending empty Start/Stop pairs, returning the time one pair takes.
+pub fn calibrate_overhead(pairs: u32) -> Duration {
+    client::calibrate_overhead::<AsyncLibrary>(pairs)
+}
+
+/// The last error of the library, such as the server not being reachable, if any.
+pub fn last_error() -> Option<String> {
+    STATE.last_error()
+}
+
+/// Send the queued markers to the server now, instead of waiting for the background thread.
+pub fn flush() {
+    let mut connection = STATE.lock_connection();
+
+    // Popped under the lock, so the batches reach the server in the order the markers were queued
+    let queue = queue();
+    let mut frames = Vec::new();
+    while let Some(mut message) = queue.pop() {
+        let registration = matches!(message, ProcessUnderTestMessage::RegisterRegion { .. });
+        if let ProcessUnderTestMessage::Marker(packet) = &mut message {
+            packet.sequence = connection.next_sequence();
+        }
+        let frame = match protocol::encode_message(&message) {
+            Ok(frame) => frame,
+            Err(err) => {
+                STATE.set_last_error(format!("Could not encode message: {}", err));
+                continue;
+            }
+        };
+
+        // Registrations are sent over every later connection as well, after the markers queued before them
+        if registration {
+            if let Err(err) = connection.send(frames.drain(..)) {
+                STATE.set_last_error(err);
+            }
+            if let Err(err) = connection.send_preamble(frame) {
+                STATE.set_last_error(err);
+            }
+        } else {
+            frames.push(frame);
+        }
+    }
+    if let Err(err) = connection.send(frames) {
+        STATE.set_last_error(err);
+    }
+}
+
+// Runs when the library is loaded, before the process under test starts its threads
+#[cfg(target_os = "linux")]
+#[used]
+#[link_section = ".init_array"]
+static EXPORT_SESSION: extern "C" fn() = {
+    extern "C" fn export_session() {
+        unsafe { client::export_session() };
+    }
+    export_session
+};
+
+fn queue() -> &'static SegQueue<ProcessUnderTestMessage> {
+    let mut queue = QUEUE.load(Ordering::Acquire);
+    if queue.is_null() {
+        let new_queue = Box::into_raw(Box::new(SegQueue::new()));
+        queue = match QUEUE.compare_exchange(
+            ptr::null_mut(),
+            new_queue,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => new_queue,
+            Err(queue) => {
+                drop(unsafe { Box::from_raw(new_queue) });
+                queue
+            }
+        };
+    }
+    // Queues are never freed, a replaced queue is leaked
+    unsafe { &*queue }
+}
+
+fn start_worker() {
+    INIT.call_once(|| {
+        PROCESS_ID.store(process::id(), Ordering::Relaxed);
+        let mut connection = STATE.lock_connection();
+        connection.set_capacity(buffer_capacity());
+        let sent = protocol::encode_message(&session_message())
+            .map_err(|err| format!("Could not encode session: {}", err))
+            .and_then(|frame| connection.send_preamble(frame));
+        if let Err(err) = sent {
+            STATE.set_last_error(err);
+        }
+        drop(connection);
+
+        if let Some(micros) = env::var(BATCH_INTERVAL_VAR)
+            .ok()
+            .and_then(|micros| micros.parse().ok())
+        {
+            BATCH_INTERVAL_MICROS.store(micros, Ordering::Relaxed);
+        }
+
+        #[cfg(unix)]
+        unsafe {
+            // The markers queued since the last batch are sent when the process exits. Windows
+            // stops the other threads before its exit hooks, which could hold the connection, so
+            // there the markers are only sent by the background thread or thor_async_flush_rapl()
+            libc::atexit(flush_at_exit);
+            libc::pthread_atfork(
+                Some(prepare_fork),
+                Some(after_fork_in_parent),
+                Some(after_fork_in_child),
+            );
+        }
+
+        STATE.schedule_calibration();
+    });
+
+    if !WORKER_STARTED.load(Ordering::Relaxed) && !WORKER_STARTED.swap(true, Ordering::AcqRel) {
+        let batch_interval = Duration::from_micros(BATCH_INTERVAL_MICROS.load(Ordering::Relaxed));
+        let worker = thread::Builder::new()
+            .name("thor-batcher".to_string())
+            .spawn(move || loop {
+                thread::sleep(batch_interval);
+                flush();
+            });
+        if let Err(err) = worker {
+            STATE.set_last_error(format!("Could not start the background thread: {}", err));
+        }
+    }
+
+    run_pending_calibration::<AsyncLibrary>();
+}
+
+#[cfg(unix)]
+extern "C" fn flush_at_exit() {
+    flush();
+}
+
+#[cfg(unix)]
+extern "C" fn prepare_fork() {
+    STATE.prepare_fork();
+}
+
+#[cfg(unix)]
+extern "C" fn after_fork_in_parent() {
+    STATE.after_fork_in_parent();
+}
+
+// The child queues its own markers and sends them with a session message of its own
+#[cfg(unix)]
+extern "C" fn after_fork_in_child() {
+    PROCESS_ID.store(process::id(), Ordering::Relaxed);
+    QUEUE.store(Box::into_raw(Box::new(SegQueue::new())), Ordering::Release);
+    WORKER_STARTED.store(false, Ordering::Relaxed);
+    STATE.after_fork_in_child();
+}
@@ -0,0 +1,95 @@
+use shared_lib_async::ffi::{
+    thor_async_flush_rapl, thor_async_last_error, thor_async_start_rapl,
+    thor_async_stop_rapl_with_count,
+};
+use std::{
+    env,
+    ffi::CString,
+    io::{Read, Write},
+    net::TcpListener,
+    ptr, thread,
+};
+use thor_shared::{
+    protocol::{
+        decode_frame_length, decode_message, encode_handshake, RegionTable, FRAME_HEADER_LENGTH,
+    },
+    ProcessUnderTestMessage, ProcessUnderTestPacketOperation,
+};
+
+const THREADS: usize = 4;
+const PAIRS: usize = 100;
+
+#[test]
+fn test_markers_are_batched_in_order() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    env::set_var("THOR_TRANSPORT", "tcp");
+    env::set_var("THOR_ADDR", listener.local_addr().unwrap().to_string());
+
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut handshake = [0; 3];
+        stream.read_exact(&mut handshake).unwrap();
+        stream.write_all(&encode_handshake()).unwrap();
+
+        // Every region id is registered once, before the markers using it
+        let mut region_table = RegionTable::default();
+        let mut registrations = 0;
+        let mut packets = Vec::new();
+        while packets.len() < THREADS * PAIRS * 2 {
+            let mut header = [0; FRAME_HEADER_LENGTH];
+            stream.read_exact(&mut header).unwrap();
+            let mut payload = vec![0; decode_frame_length(header).unwrap()];
+            stream.read_exact(&mut payload).unwrap();
+            let message = decode_message(&payload).unwrap();
+            if let ProcessUnderTestMessage::RegisterRegion { .. } = message {
+                registrations += 1;
+            }
+            if let Some(packet) = region_table.resolve(message).unwrap() {
+                packets.push(packet);
+            }
+        }
+        assert_eq!(registrations, PAIRS);
+        packets
+    });
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| {
+            thread::spawn(|| {
+                for pair in 0..PAIRS {
+                    let id = CString::new(format!("Batched{}", pair)).unwrap();
+                    unsafe { thor_async_start_rapl(id.as_ptr()) };
+                    unsafe { thor_async_stop_rapl_with_count(id.as_ptr(), pair as u64) };
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    thor_async_flush_rapl();
+
+    // The markers of each thread arrive in the order they were taken
+    let packets = server.join().unwrap();
+    // Numbered in the order the batches were sent
+    for (sequence, packet) in packets.iter().enumerate() {
+        assert_eq!(packet.sequence, Some(sequence as u64));
+    }
+    let mut thread_ids: Vec<usize> = packets.iter().map(|packet| packet.thread_id).collect();
+    thread_ids.sort();
+    thread_ids.dedup();
+    assert_eq!(thread_ids.len(), THREADS);
+    for thread_id in thread_ids {
+        let thread_packets: Vec<_> = packets
+            .iter()
+            .filter(|packet| packet.thread_id == thread_id)
+            .collect();
+        for (pair, markers) in thread_packets.chunks(2).enumerate() {
+            assert_eq!(markers[0].id, format!("Batched{}", pair));
+            assert_eq!(markers[0].operation, ProcessUnderTestPacketOperation::Start);
+            assert_eq!(markers[1].operation, ProcessUnderTestPacketOperation::Stop);
+            assert_eq!(markers[1].work_count, Some(pair as u64));
+            assert!(markers[0].timestamp <= markers[1].timestamp);
+        }
+    }
+    assert_eq!(unsafe { thor_async_last_error(ptr::null_mut(), 0) }, 0);
+}
@@ -1,2 +1,4 @@
 pub mod ffi;
-mod library;
+pub mod library;
+#[cfg(target_os = "linux")]
+mod transport;
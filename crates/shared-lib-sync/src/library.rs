@@ -1,69 +1,258 @@
-use std::{
-    io::Write,
-    net::TcpStream,
-    process,
-    sync::{Mutex, Once},
-    time::SystemTime,
+#[cfg(target_os = "linux")]
+use crate::transport::create_shm_ring;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::Ordering;
+use std::{sync::Once, time::Duration};
+#[cfg(target_os = "linux")]
+use thor_shared::shm_ring::MarkerRecord;
+use thor_shared::{
+    client::{
+        self, buffer_capacity, run_pending_calibration, session_message, LibraryState,
+        MarkerLibrary,
+    },
+    protocol, MarkerPacket, ProcessUnderTestMessage,
 };
-use thor_shared::{ConnectionType, ProcessUnderTestPacket, ProcessUnderTestPacketOperation};
 
 static STREAM_INIT: Once = Once::new();
 
-static CONNECTION: Mutex<Option<TcpStream>> = Mutex::new(None);
+static STATE: LibraryState = LibraryState::new();
 
-const ADDRESS: &str = "127.0.0.1:5050";
+// Set instead of the connection when the markers are written to shared memory
+#[cfg(target_os = "linux")]
+static SHM_RING: std::sync::OnceLock<crate::transport::ShmConnection> = std::sync::OnceLock::new();
+
+// Set in a forked child, which sends its markers over a socket instead of its parent's ring
+#[cfg(target_os = "linux")]
+static SHM_RING_INHERITED: std::sync::atomic::AtomicBool =
+    std::sync::atomic::AtomicBool::new(false);
+
+// Sends every marker right away, from the thread taking it
+struct SyncLibrary;
+
+impl MarkerLibrary for SyncLibrary {
+    fn state() -> &'static LibraryState {
+        &STATE
+    }
+
+    fn init() {
+        connect();
+    }
+
+    fn send(marker: MarkerPacket) {
+        send_packet(marker);
+    }
+
+    // The server reads the ids of a ring from its records
+    fn send_registration(handle: u32, id: &str) {
+        if !uses_shm_ring() {
+            STATE.send_registration(handle, id);
+        }
+    }
+}
 
 pub fn start_rapl(id: impl AsRef<str>) {
-    let packet = ProcessUnderTestPacket {
-        id: id.as_ref().to_string(),
-        process_id: process::id(),
-        thread_id: thread_id::get(),
-        operation: ProcessUnderTestPacketOperation::Start,
-        timestamp: SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos(),
-    };
+    client::start_rapl::<SyncLibrary>(id);
+}
 
-    send_packet(packet);
+/// Start a region with key/value attributes, which are reported with its results.
+pub fn start_rapl_with_attrs(id: impl AsRef<str>, attributes: &[(&str, &str)]) {
+    client::start_rapl_with_attrs::<SyncLibrary>(id, attributes);
 }
 
 pub fn stop_rapl(id: impl AsRef<str>) {
-    let packet = ProcessUnderTestPacket {
-        id: id.as_ref().to_string(),
-        process_id: process::id(),
-        thread_id: thread_id::get(),
-        operation: ProcessUnderTestPacketOperation::Stop,
-        timestamp: SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos(),
-    };
+    client::stop_rapl::<SyncLibrary>(id);
+}
+
+/// Stop a region that processed the given number of work units, so the server can report the energy per unit.
+pub fn stop_rapl_with_count(id: impl AsRef<str>, work_count: u64) {
+    client::stop_rapl_with_count::<SyncLibrary>(id, work_count);
+}
+
+/// Mark a point in time, such as "cache warmed", which is reported with the energy counters at that instant.
+pub fn mark_rapl(id: impl AsRef<str>) {
+    client::mark_rapl::<SyncLibrary>(id);
+}
+
+/// The handle of a region id, which the markers send instead of the id. The id is sent to the server once per connection.
+pub fn register_region(id: impl AsRef<str>) -> u32 {
+    client::register_region::<SyncLibrary>(id)
+}
+
+pub fn start_rapl_handle(handle: u32) {
+    client::start_rapl_handle::<SyncLibrary>(handle);
+}
+
+pub fn stop_rapl_handle(handle: u32) {
+    client::stop_rapl_handle::<SyncLibrary>(handle);
+}
+
+pub fn mark_rapl_handle(handle: u32) {
+    client::mark_rapl_handle::<SyncLibrary>(handle);
+}
+
+/// Measure the cost of the markers by sending empty Start/Stop pairs, returning the time one pair takes.
+pub fn calibrate_overhead(pairs: u32) -> Duration {
+    client::calibrate_overhead::<SyncLibrary>(pairs)
+}
 
-    send_packet(packet);
+/// The last error of the library, such as the server not being reachable, if any.
+pub fn last_error() -> Option<String> {
+    STATE.last_error()
 }
 
-fn send_packet(packet: ProcessUnderTestPacket) {
+// Runs when the library is loaded, before the process under test starts its threads
+#[cfg(target_os = "linux")]
+#[used]
+#[link_section = ".init_array"]
+static EXPORT_SESSION: extern "C" fn() = {
+    extern "C" fn export_session() {
+        unsafe { client::export_session() };
+    }
+    export_session
+};
+
+fn connect() {
     STREAM_INIT.call_once(|| {
-        // making connection
-        let mut connection = TcpStream::connect(ADDRESS).unwrap();
+        let mut connection = STATE.lock_connection();
+        connection.set_capacity(buffer_capacity());
+        // Sent over the socket connections of the process and of its forked children
+        if !connect_shm_ring() {
+            let sent = protocol::encode_message(&session_message())
+                .map_err(|err| format!("Could not encode session: {}", err))
+                .and_then(|frame| connection.send_preamble(frame))
+                .and_then(|_| connection.flush());
+            if let Err(err) = sent {
+                STATE.set_last_error(err);
+            }
+        }
+        drop(connection);
 
-        // indicating that this is a process under test process
-        connection
-            .write_all(&[ConnectionType::ProcessUnderTest as u8])
-            .unwrap();
-        // TODO: Consider sending PID here to identify the process, as PID does not change.
-        // Then remove it from the packet
+        #[cfg(unix)]
+        unsafe {
+            libc::pthread_atfork(
+                Some(prepare_fork),
+                Some(after_fork_in_parent),
+                Some(after_fork_in_child),
+            );
+        }
 
-        *CONNECTION.lock().unwrap() = Some(connection);
+        STATE.schedule_calibration();
     });
 
-    let serialized = bincode::serialize(&packet).unwrap();
+    run_pending_calibration::<SyncLibrary>();
+}
+
+#[cfg(unix)]
+extern "C" fn prepare_fork() {
+    STATE.prepare_fork();
+}
+
+#[cfg(unix)]
+extern "C" fn after_fork_in_parent() {
+    STATE.after_fork_in_parent();
+}
+
+#[cfg(unix)]
+extern "C" fn after_fork_in_child() {
+    #[cfg(target_os = "linux")]
+    if let Some(ring) = SHM_RING.get() {
+        ring.close_inherited();
+        SHM_RING_INHERITED.store(true, Ordering::Relaxed);
+    }
+    STATE.after_fork_in_child();
+}
+
+// Returns false if the shared memory transport is not selected
+#[cfg(target_os = "linux")]
+fn connect_shm_ring() -> bool {
+    match create_shm_ring() {
+        Some(Ok(connection)) => {
+            let _ = SHM_RING.set(connection);
+            true
+        }
+        // Falls back to the socket, so the markers are not lost
+        Some(Err(err)) => {
+            STATE.set_last_error(format!(
+                "Could not hand the shared memory ring to the server: {}",
+                err
+            ));
+            false
+        }
+        None => false,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_shm_ring() -> bool {
+    false
+}
+
+// The ring records of this thread, indexed by the region handles
+#[cfg(target_os = "linux")]
+thread_local! {
+    static RECORDS: std::cell::RefCell<Vec<Option<MarkerRecord>>> =
+        const { std::cell::RefCell::new(Vec::new()) };
+}
+
+#[cfg(target_os = "linux")]
+fn uses_shm_ring() -> bool {
+    shm_ring().is_some()
+}
+
+#[cfg(target_os = "linux")]
+fn shm_ring() -> Option<&'static thor_shared::shm_ring::ShmRing> {
+    SHM_RING
+        .get()
+        .filter(|_| !SHM_RING_INHERITED.load(Ordering::Relaxed))
+        .map(|connection| &connection.ring)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn uses_shm_ring() -> bool {
+    false
+}
+
+fn send_packet(mut packet: MarkerPacket) {
+    // The server reads the ring, the attributes do not fit in its records and are left out
+    #[cfg(target_os = "linux")]
+    if let Some(ring) = shm_ring() {
+        RECORDS.with(|records| {
+            let mut records = records.borrow_mut();
+            let index = packet.region as usize;
+            if records.len() <= index {
+                records.resize_with(index + 1, || None);
+            }
+            // Looked up once per thread, the markers then reuse the record of their region
+            let record = records[index].get_or_insert_with(|| MarkerRecord {
+                id: STATE.regions().id(packet.region).unwrap_or_default(),
+                thread_id: packet.thread_id,
+                operation: packet.operation,
+                timestamp: packet.timestamp,
+                cpu: packet.cpu,
+                work_count: packet.work_count,
+            });
+            record.thread_id = packet.thread_id;
+            record.operation = packet.operation;
+            record.timestamp = packet.timestamp;
+            record.cpu = packet.cpu;
+            record.work_count = packet.work_count;
+
+            // A full ring drops the marker and counts it, which the server reports
+            ring.push(record);
+        });
+        return;
+    }
 
-    let mut connection_lock = CONNECTION.lock().unwrap();
-    let stream = connection_lock.as_mut().unwrap();
+    let mut connection = STATE.lock_connection();
+    packet.sequence = connection.next_sequence();
 
-    // Write length and then the serialized packet
-    stream.write_all(&[(serialized.len() as u8)]).unwrap();
-    stream.write_all(&serialized).unwrap();
+    // The frame holds the length and then the serialized packet
+    match protocol::encode_message(&ProcessUnderTestMessage::Marker(packet)) {
+        Ok(frame) => {
+            if let Err(err) = connection.send([frame]) {
+                STATE.set_last_error(err);
+            }
+        }
+        Err(err) => STATE.set_last_error(format!("Could not encode marker: {}", err)),
+    }
 }
@@ -0,0 +1,61 @@
+use std::{
+    env, io,
+    os::unix::io::{IntoRawFd, RawFd},
+    process,
+    sync::atomic::{AtomicI32, Ordering},
+};
+use thor_shared::{
+    client::{connect_shm_ring, TRANSPORT_VAR},
+    shm_ring::ShmRing,
+};
+
+// Override the number of records of the shared memory ring
+const SHM_CAPACITY_VAR: &str = "THOR_SHM_CAPACITY";
+
+const DEFAULT_SHM_CAPACITY: u32 = 16384;
+
+// Stored in place of the connection once it is closed
+const CLOSED: RawFd = -1;
+
+/// The shared memory ring of the process and the connection it was handed to the server over,
+/// which tells the server the ring is finished when it closes.
+pub struct ShmConnection {
+    pub ring: ShmRing,
+    connection: AtomicI32,
+}
+
+impl ShmConnection {
+    /// Close the connection inherited by a forked child, so that it closes when the parent exits.
+    pub fn close_inherited(&self) {
+        let fd = self.connection.swap(CLOSED, Ordering::Relaxed);
+        if fd != CLOSED {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+impl Drop for ShmConnection {
+    fn drop(&mut self) {
+        self.close_inherited();
+    }
+}
+
+/// Create the shared memory ring of the process and hand it to the server, if the shared memory
+/// transport is selected.
+pub fn create_shm_ring() -> Option<io::Result<ShmConnection>> {
+    if env::var(TRANSPORT_VAR).ok()? != "shm" {
+        return None;
+    }
+
+    let capacity = env::var(SHM_CAPACITY_VAR)
+        .ok()
+        .and_then(|capacity| capacity.parse().ok())
+        .unwrap_or(DEFAULT_SHM_CAPACITY);
+    Some(ShmRing::create(process::id(), capacity).and_then(|ring| {
+        let connection = connect_shm_ring(&ring)?;
+        Ok(ShmConnection {
+            ring,
+            connection: AtomicI32::new(connection.into_raw_fd()),
+        })
+    }))
+}
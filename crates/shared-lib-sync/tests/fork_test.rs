@@ -0,0 +1,118 @@
+#![cfg(unix)]
+
+use shared_lib_sync::ffi::{start_rapl, stop_rapl};
+use std::{
+    env,
+    ffi::CString,
+    io::{Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    process, thread,
+};
+use thor_shared::{
+    protocol::{decode_frame_length, decode_message, encode_handshake, FRAME_HEADER_LENGTH},
+    ProcessSession, ProcessUnderTestMessage, ProcessUnderTestPacketOperation,
+};
+
+fn accept(listener: &UnixListener) -> UnixStream {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut preface = [0; 3];
+    stream.read_exact(&mut preface).unwrap();
+    stream.write_all(&encode_handshake()).unwrap();
+    stream
+}
+
+// None once the process has closed the connection
+fn read_message(stream: &mut UnixStream) -> Option<ProcessUnderTestMessage> {
+    let mut header = [0; FRAME_HEADER_LENGTH];
+    stream.read_exact(&mut header).ok()?;
+    let mut payload = vec![0; decode_frame_length(header).unwrap()];
+    stream.read_exact(&mut payload).unwrap();
+    Some(decode_message(&payload).unwrap())
+}
+
+#[test]
+fn test_forked_child_reconnects_in_session() {
+    let path = env::temp_dir().join(format!("thor-fork-test-{}.sock", process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    env::set_var("THOR_SOCKET", &path);
+    env::set_var("THOR_SESSION", "fork-test");
+
+    let server = thread::spawn(move || {
+        // The parent's session, registration and start
+        let mut parent = accept(&listener);
+        let parent_messages: Vec<_> = (0..3).map(|_| read_message(&mut parent).unwrap()).collect();
+
+        let mut child = accept(&listener);
+        let child_messages: Vec<_> = std::iter::from_fn(|| read_message(&mut child)).collect();
+        (parent_messages, child_messages)
+    });
+
+    let parent_id = CString::new("BeforeFork").unwrap();
+    unsafe { start_rapl(parent_id.as_ptr()) };
+    let child_id = unsafe { libc::fork() };
+    assert!(child_id >= 0);
+    if child_id == 0 {
+        let id = CString::new("InChild").unwrap();
+        unsafe {
+            start_rapl(id.as_ptr());
+            stop_rapl(id.as_ptr());
+            libc::_exit(0);
+        }
+    }
+    let mut status = 0;
+    unsafe { libc::waitpid(child_id, &mut status, 0) };
+    unsafe { stop_rapl(parent_id.as_ptr()) };
+
+    let (parent_messages, child_messages) = server.join().unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    let parent_session = ProcessSession {
+        session: "fork-test".to_string(),
+        process_id: process::id(),
+        parent_process_id: std::os::unix::process::parent_id(),
+    };
+    assert!(matches!(
+        &parent_messages[0],
+        ProcessUnderTestMessage::Session(session) if *session == parent_session
+    ));
+
+    // The child sends none of the parent's frames, and registers the parent's regions again
+    let child_session = ProcessSession {
+        session: "fork-test".to_string(),
+        process_id: child_id as u32,
+        parent_process_id: process::id(),
+    };
+    assert!(matches!(
+        &child_messages[0],
+        ProcessUnderTestMessage::Session(session) if *session == child_session
+    ));
+    assert!(matches!(
+        &child_messages[1],
+        ProcessUnderTestMessage::RegisterRegion { handle: 0, id } if id == "BeforeFork"
+    ));
+    assert!(matches!(
+        &child_messages[2],
+        ProcessUnderTestMessage::RegisterRegion { handle: 1, id } if id == "InChild"
+    ));
+
+    let markers: Vec<_> = child_messages[3..]
+        .iter()
+        .map(|message| match message {
+            ProcessUnderTestMessage::Marker(marker) => marker,
+            _ => panic!("Expected only markers after the registrations"),
+        })
+        .collect();
+    assert_eq!(markers.len(), 2);
+    // The child numbers its markers from 0, as a new process
+    for (sequence, marker) in markers.iter().enumerate() {
+        assert_eq!(marker.sequence, sequence as u64);
+        assert_eq!(marker.region, 1);
+        assert_eq!(marker.process_id, child_id as u32);
+        // The forking thread is the main thread of the child
+        #[cfg(target_os = "linux")]
+        assert_eq!(marker.thread_id, child_id as usize);
+    }
+    assert_eq!(markers[0].operation, ProcessUnderTestPacketOperation::Start);
+    assert_eq!(markers[1].operation, ProcessUnderTestPacketOperation::Stop);
+}
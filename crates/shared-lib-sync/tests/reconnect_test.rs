@@ -0,0 +1,86 @@
+use shared_lib_sync::ffi::{start_rapl, thor_last_error};
+use std::{
+    env,
+    ffi::{c_char, CStr, CString},
+    io::{Read, Write},
+    net::TcpListener,
+    ptr, thread,
+    time::Duration,
+};
+use thor_shared::protocol::{
+    decode_frame_length, decode_message, encode_handshake, RegionTable, FRAME_HEADER_LENGTH,
+};
+
+fn last_error() -> Option<String> {
+    let mut buffer = [0 as c_char; 256];
+    let length = unsafe { thor_last_error(buffer.as_mut_ptr(), buffer.len()) };
+    (length > 0).then(|| {
+        unsafe { CStr::from_ptr(buffer.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    })
+}
+
+fn start(id: &str) {
+    let id = CString::new(id).unwrap();
+    unsafe { start_rapl(id.as_ptr()) };
+}
+
+#[test]
+fn test_buffers_until_the_server_is_reachable() {
+    // Nothing listens on the port of a dropped listener
+    let unused_address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    env::set_var("THOR_TRANSPORT", "tcp");
+    env::set_var("THOR_ADDR", unused_address.to_string());
+    env::set_var("THOR_BUFFER_PACKETS", "4");
+    assert_eq!(unsafe { thor_last_error(ptr::null_mut(), 0) }, 0);
+
+    // Markers without a server do not panic, and those beyond the buffer are dropped
+    for i in 0..6 {
+        start(&format!("Buffered{}", i));
+    }
+    assert!(last_error().unwrap().starts_with("Dropped"));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    env::set_var("THOR_ADDR", listener.local_addr().unwrap().to_string());
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut handshake = [0; 3];
+        stream.read_exact(&mut handshake).unwrap();
+        stream.write_all(&encode_handshake()).unwrap();
+
+        // The regions registered while disconnected are sent first
+        let mut region_table = RegionTable::default();
+        let mut ids = Vec::new();
+        while ids.len() < 5 {
+            let mut header = [0; FRAME_HEADER_LENGTH];
+            stream.read_exact(&mut header).unwrap();
+            let mut payload = vec![0; decode_frame_length(header).unwrap()];
+            stream.read_exact(&mut payload).unwrap();
+            let message = decode_message(&payload).unwrap();
+            if let Some(packet) = region_table.resolve(message).unwrap() {
+                ids.push(packet.id);
+            }
+        }
+        ids
+    });
+
+    // Reconnecting is only attempted once the interval has passed
+    thread::sleep(Duration::from_millis(1100));
+    start("Reconnected");
+
+    let ids = server.join().unwrap();
+    assert_eq!(
+        ids,
+        [
+            "Buffered0",
+            "Buffered1",
+            "Buffered2",
+            "Buffered3",
+            "Reconnected"
+        ]
+    );
+}
@@ -0,0 +1,58 @@
+#![cfg(target_os = "linux")]
+
+use shared_lib_sync::ffi::{start_rapl, stop_rapl_with_count};
+use std::{
+    env,
+    ffi::CString,
+    fs,
+    io::{Read, Write},
+    os::unix::net::UnixListener,
+    process, thread,
+};
+use thor_shared::{
+    protocol,
+    shm_ring::{receive_fd, ShmRing},
+    ConnectionType, ProcessUnderTestPacketOperation,
+};
+
+#[test]
+fn test_markers_written_to_shared_memory() {
+    let directory = env::temp_dir().join(format!("thor-shm-lib-{}", process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("thor-server.sock");
+    let listener = UnixListener::bind(&path).unwrap();
+    env::set_var("THOR_TRANSPORT", "shm");
+    env::set_var("THOR_SOCKET", &path);
+
+    // Accepts the ring the way the server does
+    let server = thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut connection_type = [0; 1];
+        socket.read_exact(&mut connection_type).unwrap();
+        assert_eq!(connection_type[0], ConnectionType::ShmRing as u8);
+        let mut handshake = [0; 2];
+        socket.read_exact(&mut handshake).unwrap();
+        socket.write_all(&protocol::encode_handshake()).unwrap();
+        let ring = ShmRing::from_fd(receive_fd(&socket).unwrap()).unwrap();
+        socket.write_all(&[1]).unwrap();
+        (ring, socket)
+    });
+
+    let id = CString::new("OverSharedMemory").unwrap();
+    unsafe { start_rapl(id.as_ptr()) };
+    unsafe { stop_rapl_with_count(id.as_ptr(), 10) };
+
+    let (ring, _socket) = server.join().unwrap();
+    assert_eq!(ring.process_id(), process::id());
+
+    let start = ring.pop().unwrap();
+    let stop = ring.pop().unwrap();
+    assert_eq!(start.id, "OverSharedMemory");
+    assert_eq!(start.operation, ProcessUnderTestPacketOperation::Start);
+    assert_eq!(stop.operation, ProcessUnderTestPacketOperation::Stop);
+    assert_eq!(stop.work_count, Some(10));
+    assert_eq!(start.thread_id, stop.thread_id);
+    assert!(start.timestamp <= stop.timestamp);
+    assert_eq!(ring.pop(), None);
+    fs::remove_dir_all(directory).unwrap();
+}
@@ -1,8 +1,77 @@
-use shared_lib_sync::ffi::{start_rapl, stop_rapl};
-use std::ffi::CString;
+use shared_lib_sync::ffi::{
+    calibrate_rapl, mark_rapl, start_rapl, start_rapl_with_attrs, stop_rapl, stop_rapl_with_count,
+};
+use std::{
+    env,
+    ffi::CString,
+    io::{Read, Write},
+    net::TcpListener,
+    process,
+    sync::{Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+use thor_shared::{
+    protocol::{
+        decode_frame_length, decode_message, encode_handshake, RegionTable, FRAME_HEADER_LENGTH,
+    },
+    ProcessUnderTestPacket, ProcessUnderTestPacketOperation, CALIBRATION_PAIR_ID,
+};
+
+// The packets received by a local server, shared by the tests as they share the library's connection
+static RECEIVED: Mutex<Vec<ProcessUnderTestPacket>> = Mutex::new(Vec::new());
+
+fn start_server() {
+    static SERVER: OnceLock<()> = OnceLock::new();
+    SERVER.get_or_init(|| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        env::set_var("THOR_TRANSPORT", "tcp");
+        env::set_var("THOR_ADDR", listener.local_addr().unwrap().to_string());
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut handshake = [0; 3];
+            stream.read_exact(&mut handshake).unwrap();
+            stream.write_all(&encode_handshake()).unwrap();
+
+            let mut region_table = RegionTable::default();
+            loop {
+                let mut header = [0; FRAME_HEADER_LENGTH];
+                stream.read_exact(&mut header).unwrap();
+                let mut payload = vec![0; decode_frame_length(header).unwrap()];
+                stream.read_exact(&mut payload).unwrap();
+                let message = decode_message(&payload).unwrap();
+                if let Some(packet) = region_table.resolve(message).unwrap() {
+                    RECEIVED.lock().unwrap().push(packet);
+                }
+            }
+        });
+    });
+}
+
+// Waits for the given number of packets of the region id
+fn received<T>(id: &str, count: usize, f: impl Fn(&ProcessUnderTestPacket) -> T) -> Vec<T> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let packets: Vec<T> = RECEIVED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|packet| packet.id == id)
+            .map(&f)
+            .collect();
+        if packets.len() >= count {
+            assert_eq!(packets.len(), count);
+            return packets;
+        }
+        assert!(Instant::now() < deadline, "Did not receive {}", id);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
 
 #[test]
 fn test_send_function() {
+    start_server();
     let func1 = CString::new("Function1").unwrap();
     unsafe { start_rapl(func1.as_ptr()) };
     unsafe { stop_rapl(func1.as_ptr()) };
@@ -10,4 +79,94 @@ fn test_send_function() {
     let func2 = CString::new("Function2").unwrap();
     unsafe { start_rapl(func2.as_ptr()) };
     unsafe { stop_rapl(func2.as_ptr()) };
+
+    for id in ["Function1", "Function2"] {
+        let packets = received(id, 2, |packet| {
+            (packet.operation, packet.process_id, packet.thread_id)
+        });
+        assert_eq!(packets[0].0, ProcessUnderTestPacketOperation::Start);
+        assert_eq!(packets[1].0, ProcessUnderTestPacketOperation::Stop);
+        assert_eq!(packets[0].1, process::id());
+        assert_eq!(packets[0].2, packets[1].2);
+    }
+}
+
+#[test]
+fn test_calibrate_overhead() {
+    start_server();
+    let pair_nanos = calibrate_rapl(10);
+    assert!(pair_nanos > 0);
+
+    let operations = received(CALIBRATION_PAIR_ID, 20, |packet| packet.operation);
+    assert!(operations.chunks(2).all(|pair| pair
+        == [
+            ProcessUnderTestPacketOperation::Start,
+            ProcessUnderTestPacketOperation::Stop
+        ]));
+}
+
+#[test]
+fn test_stop_with_work_count() {
+    start_server();
+    let func = CString::new("Function3").unwrap();
+    unsafe { start_rapl(func.as_ptr()) };
+    unsafe { stop_rapl_with_count(func.as_ptr(), 100) };
+
+    let work_counts = received("Function3", 2, |packet| packet.work_count);
+    assert_eq!(work_counts, [None, Some(100)]);
+}
+
+#[test]
+fn test_start_with_attributes() {
+    start_server();
+    let func = CString::new("Function4").unwrap();
+    let keys = [
+        CString::new("size").unwrap(),
+        CString::new("variant").unwrap(),
+    ];
+    let values = [
+        CString::new("1024").unwrap(),
+        CString::new("quick").unwrap(),
+    ];
+    let keys_ptrs: Vec<_> = keys.iter().map(|key| key.as_ptr()).collect();
+    let values_ptrs: Vec<_> = values.iter().map(|value| value.as_ptr()).collect();
+
+    unsafe {
+        start_rapl_with_attrs(
+            func.as_ptr(),
+            keys_ptrs.as_ptr(),
+            values_ptrs.as_ptr(),
+            keys.len(),
+        )
+    };
+    unsafe { stop_rapl(func.as_ptr()) };
+
+    let attributes = received("Function4", 2, |packet| packet.attributes.clone());
+    assert_eq!(
+        attributes[0],
+        [
+            ("size".to_string(), "1024".to_string()),
+            ("variant".to_string(), "quick".to_string())
+        ]
+    );
+    assert!(attributes[1].is_empty());
+}
+
+#[test]
+fn test_mark() {
+    start_server();
+    let func = CString::new("Function5").unwrap();
+    let mark = CString::new("cache warmed").unwrap();
+    unsafe { start_rapl(func.as_ptr()) };
+    unsafe { mark_rapl(mark.as_ptr()) };
+    unsafe { stop_rapl(func.as_ptr()) };
+
+    let marks = received("cache warmed", 1, |packet| {
+        (packet.operation, packet.timestamp)
+    });
+    assert_eq!(marks[0].0, ProcessUnderTestPacketOperation::Mark);
+
+    // The mark lies within the region it was sent in
+    let timestamps = received("Function5", 2, |packet| packet.timestamp);
+    assert!(timestamps[0] <= marks[0].1 && marks[0].1 <= timestamps[1]);
 }
@@ -0,0 +1,91 @@
+#![cfg(unix)]
+
+use shared_lib_sync::ffi::{
+    register_region, start_rapl, start_rapl_handle, stop_rapl, stop_rapl_handle,
+};
+use std::{
+    env,
+    ffi::CString,
+    io::{Read, Write},
+    os::unix::net::UnixListener,
+    process, thread,
+};
+use thor_shared::{
+    protocol::{decode_frame_length, decode_message, encode_handshake, FRAME_HEADER_LENGTH},
+    ConnectionType, ProcessUnderTestMessage, ProcessUnderTestPacketOperation,
+};
+
+#[test]
+fn test_prefers_unix_socket() {
+    let path = env::temp_dir().join(format!("thor-test-{}.sock", process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    env::set_var("THOR_SOCKET", &path);
+
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let mut connection_type = [0; 1];
+        stream.read_exact(&mut connection_type).unwrap();
+        assert_eq!(connection_type[0], ConnectionType::ProcessUnderTest as u8);
+        let mut handshake = [0; 2];
+        stream.read_exact(&mut handshake).unwrap();
+        stream.write_all(&encode_handshake()).unwrap();
+
+        (0..6)
+            .map(|_| {
+                let mut header = [0; FRAME_HEADER_LENGTH];
+                stream.read_exact(&mut header).unwrap();
+                let mut payload = vec![0; decode_frame_length(header).unwrap()];
+                stream.read_exact(&mut payload).unwrap();
+                decode_message(&payload).unwrap()
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let id = CString::new("OverUnixSocket").unwrap();
+    unsafe { start_rapl(id.as_ptr()) };
+    unsafe { stop_rapl(id.as_ptr()) };
+
+    // Registering an id used before gives the same handle, without sending it again
+    let handle = unsafe { register_region(id.as_ptr()) };
+    start_rapl_handle(handle);
+    stop_rapl_handle(handle);
+
+    let messages = server.join().unwrap();
+    let _ = std::fs::remove_file(&path);
+    let ProcessUnderTestMessage::Session(process_session) = &messages[0] else {
+        panic!("Expected the session first");
+    };
+    assert_eq!(process_session.process_id, process::id());
+
+    let ProcessUnderTestMessage::RegisterRegion {
+        handle: registered,
+        id,
+    } = &messages[1]
+    else {
+        panic!("Expected the region to be registered first");
+    };
+    assert_eq!(id, "OverUnixSocket");
+    assert_eq!(*registered, handle);
+
+    let operations: Vec<_> = messages[2..]
+        .iter()
+        .map(|message| match message {
+            ProcessUnderTestMessage::Marker(marker) => {
+                assert_eq!(marker.region, handle);
+                (marker.sequence, marker.operation)
+            }
+            _ => panic!("Region registered twice"),
+        })
+        .collect();
+    assert_eq!(
+        operations,
+        [
+            (0, ProcessUnderTestPacketOperation::Start),
+            (1, ProcessUnderTestPacketOperation::Stop),
+            (2, ProcessUnderTestPacketOperation::Start),
+            (3, ProcessUnderTestPacketOperation::Stop)
+        ]
+    );
+}
@@ -0,0 +1,805 @@
+use crate::{
+    protocol::{self, FrameError},
+    ConnectionType, MarkerPacket, ProcessSession, ProcessUnderTestMessage,
+    ProcessUnderTestPacketOperation, CALIBRATION_PAIR_ID, CALIBRATION_REGION_ID,
+};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    env,
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    process,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex, MutexGuard, OnceLock, PoisonError, RwLock, RwLockReadGuard,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:5050";
+
+/// Overrides the address of the server.
+pub const ADDRESS_VAR: &str = "THOR_ADDR";
+
+/// Overrides the path of the server's Unix socket.
+pub const SOCKET_PATH_VAR: &str = "THOR_SOCKET";
+
+/// Chooses the transport: "tcp", "unix" or "shm". The Unix socket is tried before TCP if unset.
+pub const TRANSPORT_VAR: &str = "THOR_TRANSPORT";
+
+/// Number of frames kept while the server can not be reached.
+pub const BUFFER_CAPACITY_VAR: &str = "THOR_BUFFER_PACKETS";
+pub const DEFAULT_BUFFER_CAPACITY: usize = 4096;
+
+/// Links the processes started by a process under test to its session. Set by the first process if unset.
+pub const SESSION_VAR: &str = "THOR_SESSION";
+
+// Keeps an unreachable remote server from stalling the marker that connects
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Markers sent while the server can not be reached only try to reconnect this often
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A connection to the server.
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Connect over the transport chosen in the environment, by default the server's Unix socket if
+/// it listens on one and TCP otherwise.
+pub fn connect_stream() -> io::Result<Box<dyn Stream>> {
+    let transport = env::var(TRANSPORT_VAR).ok();
+
+    #[cfg(unix)]
+    if transport.as_deref() != Some("tcp") {
+        match connect_unix_socket() {
+            Ok(stream) => return Ok(Box::new(stream)),
+            Err(err) if transport.as_deref() == Some("unix") => return Err(err),
+            Err(_) => {}
+        }
+    }
+
+    let address = env::var(ADDRESS_VAR).unwrap_or_else(|_| DEFAULT_ADDRESS.into());
+    let mut last_error = io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} did not resolve to an address", address),
+    );
+    for socket_address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_address, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(Box::new(stream)),
+            Err(err) => last_error = err,
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(unix)]
+fn connect_unix_socket() -> io::Result<std::os::unix::net::UnixStream> {
+    use crate::DEFAULT_UNIX_SOCKET_PATH;
+    use std::os::unix::net::UnixStream;
+
+    let path = env::var(SOCKET_PATH_VAR).unwrap_or_else(|_| DEFAULT_UNIX_SOCKET_PATH.into());
+    if !trusted_socket(&path) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a socket owned by the server", path),
+        ));
+    }
+    UnixStream::connect(path)
+}
+
+// Only sockets owned by root, the current user or the owner of a directory no one else can write
+// to can be the server's. Another user could otherwise receive the markers with a socket of their own
+#[cfg(unix)]
+fn trusted_socket(path: &str) -> bool {
+    use std::{
+        fs,
+        os::unix::fs::{FileTypeExt, MetadataExt},
+        path::Path,
+    };
+
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return false;
+    };
+    if !metadata.file_type().is_socket() {
+        return false;
+    }
+    // Safety: geteuid has no preconditions and can not fail
+    if metadata.uid() == 0 || metadata.uid() == unsafe { libc::geteuid() } {
+        return true;
+    }
+    Path::new(path)
+        .parent()
+        .and_then(|directory| fs::metadata(directory).ok())
+        .is_some_and(|directory| directory.uid() == metadata.uid() && directory.mode() & 0o002 == 0)
+}
+
+/// Connect as a process under test, checking that the server speaks the same protocol.
+pub fn connect_process_under_test() -> io::Result<Box<dyn Stream>> {
+    // making connection
+    let mut connection = connect_stream()?;
+
+    // indicating that this is a process under test process
+    connection.write_all(&[ConnectionType::ProcessUnderTest as u8])?;
+
+    // The server answers the protocol version with its own
+    connection.write_all(&protocol::encode_handshake())?;
+    let mut server_version = [0; 2];
+    connection.read_exact(&mut server_version)?;
+    protocol::check_handshake(server_version)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    // TODO: Consider sending PID here to identify the process, as PID does not change.
+    // Then remove it from the packet
+
+    Ok(connection)
+}
+
+/// Hand the shared memory ring of the process to the server over its Unix socket. The server
+/// reads the ring until the returned connection is closed, which happens when the process exits.
+#[cfg(target_os = "linux")]
+pub fn connect_shm_ring(
+    ring: &crate::shm_ring::ShmRing,
+) -> io::Result<std::os::unix::net::UnixStream> {
+    use std::os::unix::io::AsFd;
+
+    let mut connection = connect_unix_socket()?;
+    connection.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    connection.write_all(&[ConnectionType::ShmRing as u8])?;
+    connection.write_all(&protocol::encode_handshake())?;
+    let mut server_version = [0; 2];
+    connection.read_exact(&mut server_version)?;
+    protocol::check_handshake(server_version)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    // The server answers once it accepted the ring, and closes the connection if it refuses it
+    crate::shm_ring::send_fd(&connection, ring.as_fd())?;
+    let mut accepted = [0; 1];
+    connection.read_exact(&mut accepted)?;
+
+    Ok(connection)
+}
+
+/// The session of the process, starting a new one if none was inherited.
+pub fn session() -> String {
+    static SESSION: OnceLock<String> = OnceLock::new();
+    SESSION
+        .get_or_init(|| inherited_session().unwrap_or_else(new_session))
+        .clone()
+}
+
+/// Start a new session if none was inherited, so the processes forked or executed from here on join it.
+///
+/// # Safety
+///
+/// Sets an environment variable, so no other thread may run, such as from a library constructor.
+pub unsafe fn export_session() {
+    if inherited_session().is_none() {
+        env::set_var(SESSION_VAR, new_session());
+    }
+}
+
+fn inherited_session() -> Option<String> {
+    env::var(SESSION_VAR)
+        .ok()
+        .filter(|session| !session.is_empty())
+}
+
+fn new_session() -> String {
+    format!("{}-{}", process::id(), timestamp_now())
+}
+
+/// The session message, which a connection sends before any other frame.
+pub fn session_message() -> ProcessUnderTestMessage {
+    ProcessUnderTestMessage::Session(ProcessSession {
+        session: session(),
+        process_id: process::id(),
+        parent_process_id: parent_process_id(),
+    })
+}
+
+#[cfg(unix)]
+fn parent_process_id() -> u32 {
+    std::os::unix::process::parent_id()
+}
+
+#[cfg(not(unix))]
+fn parent_process_id() -> u32 {
+    0
+}
+
+/// The preamble of a forked child: its own session message followed by the regions registered before the fork.
+pub fn encode_preamble(region_ids: &[String]) -> Result<Vec<u8>, FrameError> {
+    let mut preamble = protocol::encode_message(&session_message())?;
+    for (handle, id) in region_ids.iter().enumerate() {
+        preamble.extend(protocol::encode_message(
+            &ProcessUnderTestMessage::RegisterRegion {
+                handle: handle as u32,
+                id: id.clone(),
+            },
+        )?);
+    }
+    Ok(preamble)
+}
+
+/// The buffer capacity given in the environment.
+pub fn buffer_capacity() -> usize {
+    env::var(BUFFER_CAPACITY_VAR)
+        .ok()
+        .and_then(|capacity| capacity.parse().ok())
+        .unwrap_or(DEFAULT_BUFFER_CAPACITY)
+}
+
+/// A connection to the server that keeps the frames it could not send and reconnects lazily.
+///
+/// Errors are returned for the caller to report, as markers can not fail.
+pub struct BufferedConnection {
+    stream: Option<Box<dyn Stream>>,
+    // Frames sent first over every new connection, such as the region registrations
+    preamble: Vec<u8>,
+    buffer: VecDeque<Vec<u8>>,
+    capacity: usize,
+    // Reconnecting is not attempted again before this time
+    next_attempt: Option<Instant>,
+    // Why the last connection failed, kept while waiting to reconnect
+    connection_error: String,
+    next_sequence: u64,
+}
+
+impl BufferedConnection {
+    pub const fn new(capacity: usize) -> BufferedConnection {
+        BufferedConnection {
+            stream: None,
+            preamble: Vec::new(),
+            buffer: VecDeque::new(),
+            capacity,
+            next_attempt: None,
+            connection_error: String::new(),
+            next_sequence: 0,
+        }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    /// The sequence number of the next marker. Taken while holding the connection, so the numbers follow the order of the frames.
+    pub fn next_sequence(&mut self) -> u64 {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        sequence
+    }
+
+    /// Send a frame over this connection and every connection made after it.
+    pub fn send_preamble(&mut self, frame: Vec<u8>) -> Result<(), String> {
+        self.preamble.extend_from_slice(&frame);
+        // A new connection sends the whole preamble, including the frame
+        match self.stream {
+            Some(_) => self.send([frame]),
+            None => Ok(()),
+        }
+    }
+
+    /// Send the frames, keeping those that could not be sent. Frames beyond the capacity are dropped.
+    pub fn send(&mut self, frames: impl IntoIterator<Item = Vec<u8>>) -> Result<(), String> {
+        self.buffer.extend(frames);
+        let result = self.flush();
+
+        // Without a server the markers are dropped once the buffer is full, so the program runs on
+        if self.buffer.len() > self.capacity {
+            let dropped = self.buffer.len() - self.capacity;
+            self.buffer.truncate(self.capacity);
+            return Err(format!(
+                "Dropped {} markers, {} markers are waiting for the Thor server",
+                dropped, self.capacity
+            ));
+        }
+        result
+    }
+
+    /// Send the buffered frames in order with a single write, connecting first if needed.
+    pub fn flush(&mut self) -> Result<(), String> {
+        if self.buffer.is_empty() && self.stream.is_some() {
+            return Ok(());
+        }
+        if self.stream.is_none() {
+            self.reconnect()?;
+        }
+
+        let mut reconnected = false;
+        loop {
+            let batch: Vec<u8> = self.buffer.iter().flatten().copied().collect();
+            let (written, result) = write_batch(self.stream.as_mut().unwrap(), &batch);
+
+            // Frames written in full are not sent again, the server would count them twice. A frame
+            // written in part is sent again whole, as the server drops it with the lost connection
+            let mut frames_end = 0;
+            while self
+                .buffer
+                .front()
+                .is_some_and(|frame| frames_end + frame.len() <= written)
+            {
+                frames_end += self.buffer.pop_front().unwrap().len();
+            }
+            let Err(err) = result else {
+                return Ok(());
+            };
+
+            self.stream = None;
+            // A server that accepts but does not read is only reconnected to once right away
+            if reconnected {
+                self.next_attempt = Some(Instant::now() + RECONNECT_INTERVAL);
+                self.connection_error = format!("Lost the connection to the Thor server: {}", err);
+                return Err(self.connection_error.clone());
+            }
+            self.next_attempt = None;
+            self.reconnect()?;
+            reconnected = true;
+        }
+    }
+
+    /// Forget the parent's connection and frames in a forked child, which connects again with the given preamble.
+    pub fn reset_after_fork(&mut self, preamble: Vec<u8>) {
+        // Closing the child's copy of the socket leaves the parent's connection open
+        self.stream = None;
+        self.buffer.clear();
+        self.preamble = preamble;
+        self.next_attempt = None;
+        self.next_sequence = 0;
+    }
+
+    fn reconnect(&mut self) -> Result<(), String> {
+        if self
+            .next_attempt
+            .is_some_and(|next_attempt| Instant::now() < next_attempt)
+        {
+            return Err(format!("{}, waiting to reconnect", self.connection_error));
+        }
+
+        match connect_process_under_test().and_then(|mut stream| {
+            stream.write_all(&self.preamble)?;
+            Ok(stream)
+        }) {
+            Ok(stream) => {
+                self.stream = Some(stream);
+                self.next_attempt = None;
+                Ok(())
+            }
+            Err(err) => {
+                self.next_attempt = Some(Instant::now() + RECONNECT_INTERVAL);
+                self.connection_error = format!("Could not connect to the Thor server: {}", err);
+                Err(self.connection_error.clone())
+            }
+        }
+    }
+}
+
+// Write the whole batch, returning how many bytes were written before failing
+fn write_batch(stream: &mut Box<dyn Stream>, batch: &[u8]) -> (usize, io::Result<()>) {
+    let mut written = 0;
+    while written < batch.len() {
+        match stream.write(&batch[written..]) {
+            Ok(0) => return (written, Err(io::ErrorKind::WriteZero.into())),
+            Ok(count) => written += count,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return (written, Err(err)),
+        }
+    }
+    (written, Ok(()))
+}
+
+/// The region ids registered by a process and their handles, which are the same over every connection.
+pub struct RegionRegistry {
+    handles: Mutex<Option<HashMap<String, u32>>>,
+    ids: RwLock<Vec<String>>,
+}
+
+impl RegionRegistry {
+    pub const fn new() -> RegionRegistry {
+        RegionRegistry {
+            handles: Mutex::new(None),
+            ids: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// The handle of the id. A new handle is given to on_registered while holding the registry, so
+    /// other threads can not use it before.
+    pub fn register(&self, id: &str, on_registered: impl FnOnce(u32)) -> u32 {
+        let mut handles = self.handles.lock().unwrap_or_else(PoisonError::into_inner);
+        let handles = handles.get_or_insert_with(HashMap::new);
+        if let Some(&handle) = handles.get(id) {
+            return handle;
+        }
+
+        let mut ids = self.ids.write().unwrap_or_else(PoisonError::into_inner);
+        let handle = ids.len() as u32;
+        ids.push(id.to_string());
+        drop(ids);
+        handles.insert(id.to_string(), handle);
+        on_registered(handle);
+        handle
+    }
+
+    /// Hold the registry while forking, so the child does not inherit it locked by a thread it does not have.
+    pub fn lock(&self) -> RegionRegistryGuard<'_> {
+        let handles = self.handles.lock().unwrap_or_else(PoisonError::into_inner);
+        RegionRegistryGuard {
+            ids: self.ids.read().unwrap_or_else(PoisonError::into_inner),
+            _handles: handles,
+        }
+    }
+
+    /// The id of a registered handle.
+    pub fn id(&self, handle: u32) -> Option<String> {
+        self.ids
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(handle as usize)
+            .cloned()
+    }
+}
+
+/// A locked region registry.
+pub struct RegionRegistryGuard<'a> {
+    ids: RwLockReadGuard<'a, Vec<String>>,
+    _handles: MutexGuard<'a, Option<HashMap<String, u32>>>,
+}
+
+impl RegionRegistryGuard<'_> {
+    /// The registered ids, indexed by their handles.
+    pub fn ids(&self) -> &[String] {
+        &self.ids
+    }
+}
+
+impl Default for RegionRegistry {
+    fn default() -> RegionRegistry {
+        RegionRegistry::new()
+    }
+}
+
+/// Nanoseconds since the Unix epoch, as used for the marker timestamps.
+pub fn timestamp_now() -> u128 {
+    SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+/// The kernel's id of the calling thread, so the server can look up its CPU time.
+#[cfg(target_os = "linux")]
+pub fn os_thread_id() -> usize {
+    THREAD_ID.with(|thread_id| {
+        if thread_id.get() == 0 {
+            thread_id.set(unsafe { libc::syscall(libc::SYS_gettid) as usize });
+        }
+        thread_id.get()
+    })
+}
+
+// Looked up once per thread, to keep system calls out of the markers
+#[cfg(target_os = "linux")]
+thread_local! {
+    static THREAD_ID: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Forget the thread id cached by the calling thread, which changes in a forked child.
+#[cfg(target_os = "linux")]
+pub fn forget_thread_id() {
+    THREAD_ID.with(|thread_id| thread_id.set(0));
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn forget_thread_id() {}
+
+#[cfg(not(target_os = "linux"))]
+pub fn os_thread_id() -> usize {
+    thread_id::get()
+}
+
+/// The CPU the calling thread is running on.
+#[cfg(target_os = "linux")]
+pub fn current_cpu() -> Option<u32> {
+    u32::try_from(unsafe { libc::sched_getcpu() }).ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn current_cpu() -> Option<u32> {
+    None
+}
+
+/// The state of a marker library, kept in a static so the markers can reach it.
+pub struct LibraryState {
+    connection: Mutex<BufferedConnection>,
+    // Handles of the region ids, shared by every thread and connection
+    regions: RegionRegistry,
+    // The last error of the library, as the markers can not return errors
+    last_error: Mutex<Option<String>>,
+    // Number of empty pairs to calibrate with when starting, taken from THOR_CALIBRATION_PAIRS
+    pending_calibration: AtomicU32,
+}
+
+impl LibraryState {
+    pub const fn new() -> LibraryState {
+        LibraryState {
+            connection: Mutex::new(BufferedConnection::new(DEFAULT_BUFFER_CAPACITY)),
+            regions: RegionRegistry::new(),
+            last_error: Mutex::new(None),
+            pending_calibration: AtomicU32::new(0),
+        }
+    }
+
+    // A marker panicking while holding the lock does not stop the others
+    pub fn lock_connection(&self) -> MutexGuard<'_, BufferedConnection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn regions(&self) -> &RegionRegistry {
+        &self.regions
+    }
+
+    /// The last error of the library, such as the server not being reachable, if any.
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn set_last_error(&self, message: String) {
+        *self
+            .last_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(message);
+    }
+
+    /// Send the registration of a region id now, and over every later connection.
+    pub fn send_registration(&self, handle: u32, id: &str) {
+        let registration = ProcessUnderTestMessage::RegisterRegion {
+            handle,
+            id: id.to_string(),
+        };
+        match protocol::encode_message(&registration) {
+            Ok(frame) => {
+                if let Err(err) = self.lock_connection().send_preamble(frame) {
+                    self.set_last_error(err);
+                }
+            }
+            Err(err) => self.set_last_error(format!("Could not encode region: {}", err)),
+        }
+    }
+
+    /// Take the calibration asked for in the environment, which is done with the first marker.
+    pub fn schedule_calibration(&self) {
+        let calibration_pairs = env::var(CALIBRATION_PAIRS_VAR)
+            .ok()
+            .and_then(|pairs| pairs.parse().ok())
+            .unwrap_or(0);
+        self.pending_calibration
+            .store(calibration_pairs, Ordering::SeqCst);
+    }
+
+    /// Take the locks of the library before forking, so the child does not inherit them held by a thread it does not have.
+    pub fn prepare_fork(&'static self) {
+        // The registry is taken first, as registrations are sent while holding it
+        let guards = (self.regions.lock(), self.lock_connection());
+        FORK_GUARDS.with(|fork_guards| fork_guards.borrow_mut().push((self.address(), guards)));
+    }
+
+    pub fn after_fork_in_parent(&'static self) {
+        self.take_fork_guards();
+    }
+
+    /// Forget the parent's connection in the child, which connects on its next marker with a
+    /// session message of its own, so the server links it to its parent.
+    pub fn after_fork_in_child(&'static self) {
+        forget_thread_id();
+        let Some((regions, mut connection)) = self.take_fork_guards() else {
+            return;
+        };
+        match encode_preamble(regions.ids()) {
+            Ok(preamble) => connection.reset_after_fork(preamble),
+            Err(err) => self.set_last_error(format!("Could not encode session: {}", err)),
+        }
+    }
+
+    fn take_fork_guards(&'static self) -> Option<ForkGuards> {
+        FORK_GUARDS.with(|fork_guards| {
+            let mut fork_guards = fork_guards.borrow_mut();
+            let position = fork_guards
+                .iter()
+                .position(|(address, _)| *address == self.address())?;
+            Some(fork_guards.remove(position).1)
+        })
+    }
+
+    // Tells apart the libraries linked into the same process
+    fn address(&self) -> usize {
+        self as *const LibraryState as usize
+    }
+}
+
+impl Default for LibraryState {
+    fn default() -> LibraryState {
+        LibraryState::new()
+    }
+}
+
+const CALIBRATION_PAIRS_VAR: &str = "THOR_CALIBRATION_PAIRS";
+
+type ForkGuards = (
+    RegionRegistryGuard<'static>,
+    MutexGuard<'static, BufferedConnection>,
+);
+
+thread_local! {
+    // The locks taken while forking, held by the forking thread until the fork is done
+    static FORK_GUARDS: RefCell<Vec<(usize, ForkGuards)>> = const { RefCell::new(Vec::new()) };
+
+    // Every thread looks up the ids it uses once per library, so markers only take the registry's lock for new ids
+    static HANDLES: RefCell<HashMap<usize, HashMap<String, u32>>> = RefCell::new(HashMap::new());
+}
+
+/// The parts a marker library implements itself. The markers are the same for every library.
+pub trait MarkerLibrary {
+    fn state() -> &'static LibraryState;
+
+    /// Connect or start the library if needed, called before every marker.
+    fn init();
+
+    /// Send or queue a marker.
+    fn send(marker: MarkerPacket);
+
+    /// Send or queue the registration of a region id, before any marker using its handle.
+    fn send_registration(handle: u32, id: &str);
+
+    fn process_id() -> u32 {
+        process::id()
+    }
+}
+
+pub fn start_rapl<L: MarkerLibrary>(id: impl AsRef<str>) {
+    start_rapl_handle::<L>(register_region::<L>(id));
+}
+
+/// Start a region with key/value attributes, which are reported with its results.
+pub fn start_rapl_with_attrs<L: MarkerLibrary>(id: impl AsRef<str>, attributes: &[(&str, &str)]) {
+    send_marker::<L>(
+        register_region::<L>(id),
+        ProcessUnderTestPacketOperation::Start,
+        None,
+        attributes
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+    );
+}
+
+pub fn stop_rapl<L: MarkerLibrary>(id: impl AsRef<str>) {
+    stop_rapl_handle::<L>(register_region::<L>(id));
+}
+
+/// Stop a region that processed the given number of work units, so the server can report the energy per unit.
+pub fn stop_rapl_with_count<L: MarkerLibrary>(id: impl AsRef<str>, work_count: u64) {
+    send_marker::<L>(
+        register_region::<L>(id),
+        ProcessUnderTestPacketOperation::Stop,
+        Some(work_count),
+        Vec::new(),
+    );
+}
+
+/// Mark a point in time, such as "cache warmed", which is reported with the energy counters at that instant.
+pub fn mark_rapl<L: MarkerLibrary>(id: impl AsRef<str>) {
+    mark_rapl_handle::<L>(register_region::<L>(id));
+}
+
+/// The handle of a region id, which the markers send instead of the id. The id is sent to the server once per connection.
+pub fn register_region<L: MarkerLibrary>(id: impl AsRef<str>) -> u32 {
+    let id = id.as_ref();
+    let state = L::state();
+    let cached = HANDLES.with(|handles| {
+        handles
+            .borrow()
+            .get(&state.address())
+            .and_then(|handles| handles.get(id).copied())
+    });
+    if let Some(handle) = cached {
+        return handle;
+    }
+
+    L::init();
+    let handle = state
+        .regions
+        .register(id, |handle| L::send_registration(handle, id));
+    HANDLES.with(|handles| {
+        handles
+            .borrow_mut()
+            .entry(state.address())
+            .or_default()
+            .insert(id.to_string(), handle)
+    });
+    handle
+}
+
+pub fn start_rapl_handle<L: MarkerLibrary>(handle: u32) {
+    send_marker::<L>(
+        handle,
+        ProcessUnderTestPacketOperation::Start,
+        None,
+        Vec::new(),
+    );
+}
+
+pub fn stop_rapl_handle<L: MarkerLibrary>(handle: u32) {
+    send_marker::<L>(
+        handle,
+        ProcessUnderTestPacketOperation::Stop,
+        None,
+        Vec::new(),
+    );
+}
+
+pub fn mark_rapl_handle<L: MarkerLibrary>(handle: u32) {
+    send_marker::<L>(
+        handle,
+        ProcessUnderTestPacketOperation::Mark,
+        None,
+        Vec::new(),
+    );
+}
+
+fn send_marker<L: MarkerLibrary>(
+    region: u32,
+    operation: ProcessUnderTestPacketOperation,
+    work_count: Option<u64>,
+    attributes: Vec<(String, String)>,
+) {
+    L::init();
+
+    L::send(MarkerPacket {
+        region,
+        process_id: L::process_id(),
+        thread_id: os_thread_id(),
+        operation,
+        timestamp: timestamp_now(),
+        cpu: current_cpu(),
+        work_count,
+        attributes,
+        // Numbered when the marker is sent
+        sequence: 0,
+    });
+}
+
+/// Measure the cost of the markers by sending empty Start/Stop pairs, returning the time one pair takes.
+///
+/// The server uses the pairs to compute the energy of a pair, which it reports and can subtract from regions.
+pub fn calibrate_overhead<L: MarkerLibrary>(pairs: u32) -> Duration {
+    let pairs = pairs.max(1);
+
+    start_rapl::<L>(CALIBRATION_REGION_ID);
+    let start = Instant::now();
+    for _ in 0..pairs {
+        start_rapl::<L>(CALIBRATION_PAIR_ID);
+        stop_rapl::<L>(CALIBRATION_PAIR_ID);
+    }
+    let elapsed = start.elapsed();
+    stop_rapl::<L>(CALIBRATION_REGION_ID);
+
+    elapsed / pairs
+}
+
+/// Calibrate at the start of the session, before the first marker is taken. Called by `init`.
+pub fn run_pending_calibration<L: MarkerLibrary>() {
+    let pending_calibration = &L::state().pending_calibration;
+    // Read without a write first, as every marker checks it
+    if pending_calibration.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let calibration_pairs = pending_calibration.swap(0, Ordering::SeqCst);
+    if calibration_pairs > 0 {
+        calibrate_overhead::<L>(calibration_pairs);
+    }
+}
@@ -1,4 +1,9 @@
 use serde::{Deserialize, Serialize};
+
+pub mod client;
+pub mod protocol;
+#[cfg(target_os = "linux")]
+pub mod shm_ring;
 use thor_lib::RaplMeasurementJoules;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -8,17 +13,77 @@ pub struct ProcessUnderTestPacket {
     pub thread_id: usize,
     pub operation: ProcessUnderTestPacketOperation,
     pub timestamp: u128,
+    /// The CPU the thread was running on, if known
+    pub cpu: Option<u32>,
+    /// Number of work units processed by the region, given on its Stop
+    pub work_count: Option<u64>,
+    /// Key/value attributes of the marker, such as the input size or the algorithm variant
+    pub attributes: Vec<(String, String)>,
+    /// Number of the marker in the order the process sent it, if sent by the library
+    pub sequence: Option<u64>,
 }
 
+/// A frame sent by a process under test.
 #[derive(Debug, Serialize, Deserialize)]
+pub enum ProcessUnderTestMessage {
+    /// Names the handle used by the markers sent after it over the connection
+    RegisterRegion {
+        handle: u32,
+        id: String,
+    },
+    Marker(MarkerPacket),
+    /// Sent first over every connection, linking the process to the processes it was started by
+    Session(ProcessSession),
+}
+
+/// The session a process under test belongs to, shared by the processes it forks or executes.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ProcessSession {
+    pub session: String,
+    pub process_id: u32,
+    pub parent_process_id: u32,
+}
+
+/// A marker as sent by a process under test, with its region id replaced by a registered handle.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MarkerPacket {
+    pub region: u32,
+    pub process_id: u32,
+    pub thread_id: usize,
+    pub operation: ProcessUnderTestPacketOperation,
+    pub timestamp: u128,
+    pub cpu: Option<u32>,
+    pub work_count: Option<u64>,
+    pub attributes: Vec<(String, String)>,
+    /// Counts up from 0 over the connections of the process, so the server can tell lost markers
+    pub sequence: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessUnderTestPacketOperation {
     Start,
     Stop,
+    /// A point in time annotation, outside of the regions
+    Mark,
 }
 
+/// Unix domain socket processes under test try before falling back to TCP. Its directory is only
+/// writable by the server's user, so other users can not put a socket of their own there.
+pub const DEFAULT_UNIX_SOCKET_PATH: &str = "/run/thor/thor-server.sock";
+
+/// Region wrapping the empty regions of an overhead calibration.
+pub const CALIBRATION_REGION_ID: &str = "thor::calibration";
+
+/// The empty regions measured by an overhead calibration.
+pub const CALIBRATION_PAIR_ID: &str = "thor::calibration_pair";
+
 pub enum ConnectionType {
     ProcessUnderTest = 0,
     Client = 1,
+    /// Newline-delimited text markers, for scripts that can not use the library
+    Text = 2,
+    /// Hands the shared memory ring of a process to the server, only over the Unix socket
+    ShmRing = 3,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -26,4 +91,269 @@ pub struct ClientPacket {
     pub process_under_test_packet: ProcessUnderTestPacket,
     pub rapl_measurement: RaplMeasurementJoules,
     pub pkg_overflow: u32,
+    /// Set on Stop packets made up by the server for regions left open when a process disconnected
+    pub synthetic: bool,
+    /// CPU time of the thread and the machine when the server received the packet
+    pub cpu_time: Option<CpuTime>,
+    /// Energy counter of the packet's CPU core in joules, only available on AMD
+    pub core_energy: Option<f64>,
+    /// Number of times the thread moved to another CPU so far, when the server received the packet
+    pub cpu_migrations: Option<u64>,
+    /// How far the counters may be off from the energy used up to the packet's timestamp, in joules,
+    /// if the samples around it are known
+    pub uncertainty: Option<RaplMeasurementJoules>,
+}
+
+/// CPU time used so far, in clock ticks.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct CpuTime {
+    pub thread_ticks: u64,
+    /// Non-idle time of all CPUs of the machine
+    pub machine_ticks: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegionResult {
+    pub id: String,
+    /// Attributes given when the region was started
+    pub attributes: Vec<(String, String)>,
+    pub process_id: u32,
+    pub thread_id: usize,
+    pub start_timestamp: u128,
+    pub stop_timestamp: u128,
+    pub duration_nanos: u128,
+    /// Energy used by the region including its nested regions
+    pub energy: RaplMeasurementJoules,
+    /// Energy used by the region itself, excluding its nested regions
+    pub exclusive_energy: RaplMeasurementJoules,
+    /// Ids of the enclosing regions on the same thread, outermost first
+    pub parent_path: Vec<String>,
+    /// The region was still open when the process disconnected and was stopped by the server
+    pub synthetic_stop: bool,
+    /// Inclusive energy minus the idle power over the duration of the region
+    pub energy_above_baseline: Option<RaplMeasurementJoules>,
+    /// The idle baseline used, if one has been measured
+    pub baseline: Option<Baseline>,
+    /// Share of the machine's CPU time used by the thread during the region
+    pub cpu_time_share: Option<f64>,
+    /// Inclusive energy scaled by the CPU time share
+    pub attributed_energy: Option<RaplMeasurementJoules>,
+    pub start_cpu: Option<u32>,
+    pub stop_cpu: Option<u32>,
+    /// Whether the thread stayed on one CPU for the whole region, if its CPUs and migrations are known
+    pub single_core: Option<bool>,
+    /// Energy of the CPU core the region ran on, only available on AMD for single core regions
+    pub core_energy: Option<f64>,
+    /// Bound on the error of the inclusive energy, from the counter granularity around the start and stop
+    pub uncertainty: Option<RaplMeasurementJoules>,
+    /// Whether the package energy uncertainty is known and within the server's reliability threshold
+    pub reliable: bool,
+    /// Inclusive energy minus the instrumentation overhead of the region's own and nested markers
+    pub overhead_corrected_energy: Option<RaplMeasurementJoules>,
+    /// Number of work units processed by the region
+    pub work_count: Option<u64>,
+    /// Inclusive energy per work unit
+    pub joules_per_unit: Option<RaplMeasurementJoules>,
+    /// Work units per joule of package energy
+    pub units_per_joule: Option<f64>,
+}
+
+/// Idle power of the machine, measured while no process under test is running.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Baseline {
+    pub start_timestamp: u128,
+    pub stop_timestamp: u128,
+    /// Mean power per RAPL domain in watts
+    pub watts: RaplMeasurementJoules,
+    /// Variance of the power per RAPL domain in watts squared, between 100 ms windows
+    pub variance: RaplMeasurementJoules,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RegionError {
+    /// A Stop without a matching Start on the same thread
+    UnmatchedStop {
+        id: String,
+        process_id: u32,
+        thread_id: usize,
+        timestamp: u128,
+    },
+    /// A region still open when an enclosing region was stopped
+    UnclosedRegion {
+        id: String,
+        process_id: u32,
+        thread_id: usize,
+        start_timestamp: u128,
+        closed_by: String,
+        stop_timestamp: u128,
+    },
+}
+
+/// Power of the machine over consecutive steps, with the markers received since the previous timeline.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PowerTimeline {
+    pub start_timestamp: u128,
+    pub resolution_nanos: u128,
+    /// Mean power in watts per domain of each step
+    pub watts: Vec<RaplMeasurementJoules>,
+    /// Markers to be placed on the timeline by their timestamps, which may fall in earlier timelines
+    pub markers: Vec<TimelineMarker>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TimelineMarker {
+    pub id: String,
+    pub process_id: u32,
+    pub thread_id: usize,
+    pub operation: ProcessUnderTestPacketOperation,
+    pub timestamp: u128,
+}
+
+/// Energy used between two timestamps asked for by a client.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnergyQueryResult {
+    pub start_timestamp: u128,
+    pub stop_timestamp: u128,
+    pub energy: RaplMeasurementJoules,
+}
+
+/// A point in time annotation with the energy counters at that instant.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MarkEvent {
+    pub id: String,
+    pub attributes: Vec<(String, String)>,
+    pub process_id: u32,
+    pub thread_id: usize,
+    pub timestamp: u128,
+    pub cpu: Option<u32>,
+    /// Cumulative energy counters in joules
+    pub rapl_measurement: RaplMeasurementJoules,
+    pub pkg_overflow: u32,
+    /// Ids of the regions open on the thread, outermost first
+    pub parent_path: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MarkerInfo {
+    pub id: String,
+    pub thread_id: usize,
+    pub timestamp: u128,
+}
+
+/// Summary of the markers sent over a process under test connection, sent when it disconnects.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ValidationReport {
+    pub process_id: u32,
+    pub packets: u64,
+    /// Stops without an open region with the same id on the thread
+    pub orphaned_stops: Vec<MarkerInfo>,
+    /// Starts received twice, with the id and timestamp of the innermost region open on the thread
+    pub duplicate_starts: Vec<MarkerInfo>,
+    /// Regions that were left open and stopped by the server on disconnect
+    pub synthetic_stops: Vec<MarkerInfo>,
+    /// Markers dropped by the process because its shared memory ring was full
+    pub dropped_markers: u64,
+}
+
+impl ValidationReport {
+    pub fn is_clean(&self) -> bool {
+        self.orphaned_stops.is_empty()
+            && self.duplicate_starts.is_empty()
+            && self.synthetic_stops.is_empty()
+            && self.dropped_markers == 0
+    }
+}
+
+/// Cost of one empty Start/Stop pair, measured by a process under test calibrating its markers.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InstrumentationOverhead {
+    pub process_id: u32,
+    pub pairs: u64,
+    pub duration_nanos: f64,
+    pub energy: RaplMeasurementJoules,
+}
+
+/// Summary statistics of a series of values.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Summary {
+    pub count: u64,
+    pub mean: f64,
+    pub variance: f64,
+    pub std_dev: f64,
+    pub min: f64,
+    pub max: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    /// Bootstrapped 95% confidence interval of the mean, if there are at least two values
+    pub mean_confidence_interval: Option<(f64, f64)>,
+}
+
+/// Statistics of the results of every region with the same id.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RegionStatistics {
+    pub id: String,
+    /// Regions with the same id but different attributes are aggregated separately
+    pub attributes: Vec<(String, String)>,
+    pub duration_seconds: Summary,
+    /// Inclusive energy in joules per RAPL domain
+    pub energy: Vec<(String, Summary)>,
+    /// Inclusive energy per work unit per RAPL domain, for the regions that gave a work count
+    pub joules_per_unit: Vec<(String, Summary)>,
+    /// Work units per joule of package energy, for the regions that gave a work count
+    pub units_per_joule: Option<Summary>,
+}
+
+/// Sent to clients when a job has finished.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobSummary {
+    pub repo: String,
+    pub statistics: Vec<RegionStatistics>,
+    /// The processes under test of the job, by session
+    pub sessions: Vec<SessionTree>,
+}
+
+/// The processes of a session, as started from each other.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SessionTree {
+    pub session: String,
+    /// Processes whose parent did not connect in the same session
+    pub processes: Vec<ProcessNode>,
+    /// Markers the server never received, going by their sequence numbers
+    pub lost_markers: u64,
+    /// Markers received after a marker sent later by the same process
+    pub reordered_markers: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ProcessNode {
+    pub process_id: u32,
+    pub children: Vec<ProcessNode>,
+}
+
+/// The first message sent to a client, describing the server.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerHello {
+    pub protocol_version: u16,
+    pub server_version: String,
+    pub cpu_vendor: String,
+    /// RAPL domains enabled in the server's configuration
+    pub domains: Vec<String>,
+    pub encoding: protocol::ClientEncoding,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClientMessage {
+    Hello(ServerHello),
+    Packet(ClientPacket),
+    Region(Box<RegionResult>),
+    RegionError(RegionError),
+    ValidationReport(ValidationReport),
+    Baseline(Baseline),
+    Statistics(Vec<RegionStatistics>),
+    JobSummary(JobSummary),
+    InstrumentationOverhead(InstrumentationOverhead),
+    Mark(MarkEvent),
+    PowerTimeline(PowerTimeline),
+    EnergyQuery(Result<EnergyQueryResult, String>),
 }
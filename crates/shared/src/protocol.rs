@@ -0,0 +1,238 @@
+use crate::{ProcessUnderTestMessage, ProcessUnderTestPacket, ProcessUnderTestPacketOperation};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::HashMap, str::FromStr};
+use thiserror::Error;
+
+/// Version of the process under test protocol, sent after the connection type byte.
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// Version of the stream of client messages, sent in the hello message.
+pub const CLIENT_PROTOCOL_VERSION: u16 = 1;
+
+/// Frames longer than this are rejected, so a corrupt length can not make the server allocate gigabytes.
+pub const MAX_FRAME_LENGTH: usize = 1 << 20;
+
+/// Size of the little endian u32 length in front of every frame.
+pub const FRAME_HEADER_LENGTH: usize = 4;
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("unsupported protocol version {0}, expected {PROTOCOL_VERSION}")]
+    UnsupportedVersion(u16),
+    #[error("frame of {0} bytes exceeds the maximum of {MAX_FRAME_LENGTH} bytes")]
+    TooLong(usize),
+    #[error("invalid packet")]
+    Decode(#[from] bincode::Error),
+    #[error("invalid JSON message")]
+    Json(#[from] serde_json::Error),
+    #[error("could not encode MessagePack message")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+    #[error("invalid MessagePack message")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+    #[error("marker uses region handle {0}, which was not registered")]
+    UnknownRegion(u32),
+}
+
+/// A line of the text protocol that could not be parsed.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TextMarkerError {
+    #[error("unknown operation {0:?}, expected start, stop or mark")]
+    UnknownOperation(String),
+    #[error("missing region id")]
+    MissingId,
+    #[error("invalid {field} {value:?}")]
+    InvalidNumber { field: &'static str, value: String },
+    #[error("unexpected {0:?} after the marker")]
+    TrailingField(String),
+    #[error("timestamp {0} is outside of the samples the server retains")]
+    OutsideWindow(u128),
+    #[error("line exceeds the maximum of {MAX_FRAME_LENGTH} bytes")]
+    TooLong,
+}
+
+/// Encoding of the messages sent to a client, chosen by the client when connecting.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClientEncoding {
+    #[default]
+    Json,
+    Bincode,
+    MessagePack,
+}
+
+impl ClientEncoding {
+    /// The encoding with the given name, as given in the client's `--encoding` option.
+    pub fn from_name(name: &str) -> Option<ClientEncoding> {
+        match name {
+            "json" => Some(ClientEncoding::Json),
+            "bincode" => Some(ClientEncoding::Bincode),
+            "msgpack" => Some(ClientEncoding::MessagePack),
+            _ => None,
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, FrameError> {
+        Ok(match self {
+            ClientEncoding::Json => serde_json::to_vec(value)?,
+            ClientEncoding::Bincode => bincode::serialize(value)?,
+            ClientEncoding::MessagePack => rmp_serde::to_vec(value)?,
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, FrameError> {
+        Ok(match self {
+            ClientEncoding::Json => serde_json::from_slice(bytes)?,
+            ClientEncoding::Bincode => bincode::deserialize(bytes)?,
+            ClientEncoding::MessagePack => rmp_serde::from_slice(bytes)?,
+        })
+    }
+
+    /// Encode a value into a frame: its length as a little endian u32, followed by the encoded value.
+    pub fn encode_frame<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, FrameError> {
+        frame(self.encode(value)?)
+    }
+}
+
+/// The handshake sent by a process under test right after the connection type byte.
+pub fn encode_handshake() -> [u8; 2] {
+    PROTOCOL_VERSION.to_le_bytes()
+}
+
+/// Check the version of a handshake, the server answers with its own version either way.
+pub fn check_handshake(handshake: [u8; 2]) -> Result<(), FrameError> {
+    match u16::from_le_bytes(handshake) {
+        PROTOCOL_VERSION => Ok(()),
+        version => Err(FrameError::UnsupportedVersion(version)),
+    }
+}
+
+/// Serialize a message into a frame: its length as a little endian u32, followed by the bincode encoded message.
+pub fn encode_message(message: &ProcessUnderTestMessage) -> Result<Vec<u8>, FrameError> {
+    frame(bincode::serialize(message)?)
+}
+
+fn frame(payload: Vec<u8>) -> Result<Vec<u8>, FrameError> {
+    if payload.len() > MAX_FRAME_LENGTH {
+        return Err(FrameError::TooLong(payload.len()));
+    }
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LENGTH + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// The length of the payload following a frame header.
+pub fn decode_frame_length(header: [u8; FRAME_HEADER_LENGTH]) -> Result<usize, FrameError> {
+    let length = u32::from_le_bytes(header) as usize;
+    if length > MAX_FRAME_LENGTH {
+        return Err(FrameError::TooLong(length));
+    }
+    Ok(length)
+}
+
+/// Deserialize the payload of a frame, which must be exactly one message.
+pub fn decode_message(payload: &[u8]) -> Result<ProcessUnderTestMessage, FrameError> {
+    use bincode::Options;
+
+    // Same encoding as bincode::serialize, but trailing bytes are an error
+    Ok(bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .deserialize(payload)?)
+}
+
+/// The region ids registered over a process under test connection, by their handles.
+#[derive(Debug, Default)]
+pub struct RegionTable {
+    ids: HashMap<u32, String>,
+}
+
+impl RegionTable {
+    /// The packet of a marker with its region id resolved, or None for the other messages.
+    pub fn resolve(
+        &mut self,
+        message: ProcessUnderTestMessage,
+    ) -> Result<Option<ProcessUnderTestPacket>, FrameError> {
+        match message {
+            ProcessUnderTestMessage::RegisterRegion { handle, id } => {
+                self.ids.insert(handle, id);
+                Ok(None)
+            }
+            ProcessUnderTestMessage::Marker(marker) => {
+                let id = self
+                    .ids
+                    .get(&marker.region)
+                    .ok_or(FrameError::UnknownRegion(marker.region))?;
+                Ok(Some(ProcessUnderTestPacket {
+                    id: id.clone(),
+                    process_id: marker.process_id,
+                    thread_id: marker.thread_id,
+                    operation: marker.operation,
+                    timestamp: marker.timestamp,
+                    cpu: marker.cpu,
+                    work_count: marker.work_count,
+                    attributes: marker.attributes,
+                    sequence: Some(marker.sequence),
+                }))
+            }
+            ProcessUnderTestMessage::Session(_) => Ok(None),
+        }
+    }
+}
+
+/// Parse a line of the text protocol, `<start|stop|mark> <id> [pid] [tid] [@timestamp]`.
+///
+/// The process and thread ids default to 0, and the timestamp in nanoseconds since the Unix epoch
+/// defaults to the given time of receipt. Blank lines give no packet.
+pub fn parse_text_marker(
+    line: &str,
+    received_timestamp: u128,
+) -> Result<Option<ProcessUnderTestPacket>, TextMarkerError> {
+    let mut fields = line.split_whitespace();
+    let Some(operation) = fields.next() else {
+        return Ok(None);
+    };
+    let operation = match operation.to_ascii_lowercase().as_str() {
+        "start" => ProcessUnderTestPacketOperation::Start,
+        "stop" => ProcessUnderTestPacketOperation::Stop,
+        "mark" => ProcessUnderTestPacketOperation::Mark,
+        _ => return Err(TextMarkerError::UnknownOperation(operation.to_string())),
+    };
+    let id = fields.next().ok_or(TextMarkerError::MissingId)?;
+
+    // The timestamp is prefixed with @, so it can be given without the ids
+    let mut timestamp = None;
+    let mut process_id = None;
+    let mut thread_id = None;
+    for field in fields {
+        if timestamp.is_some() || (thread_id.is_some() && !field.starts_with('@')) {
+            return Err(TextMarkerError::TrailingField(field.to_string()));
+        }
+        match field.strip_prefix('@') {
+            Some(value) => timestamp = Some(parse_text_number(value, "timestamp")?),
+            None if process_id.is_none() => {
+                process_id = Some(parse_text_number(field, "process id")?)
+            }
+            None => thread_id = Some(parse_text_number(field, "thread id")?),
+        }
+    }
+
+    Ok(Some(ProcessUnderTestPacket {
+        id: id.to_string(),
+        process_id: process_id.unwrap_or(0),
+        thread_id: thread_id.unwrap_or(0),
+        operation,
+        timestamp: timestamp.unwrap_or(received_timestamp),
+        cpu: None,
+        work_count: None,
+        attributes: Vec::new(),
+        sequence: None,
+    }))
+}
+
+// Values that overflow the type of the field are rejected rather than truncated
+fn parse_text_number<T: FromStr>(value: &str, field: &'static str) -> Result<T, TextMarkerError> {
+    value.parse().map_err(|_| TextMarkerError::InvalidNumber {
+        field,
+        value: value.to_string(),
+    })
+}
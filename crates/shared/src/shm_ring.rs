@@ -0,0 +1,435 @@
+use crate::ProcessUnderTestPacketOperation;
+use std::{
+    fs::File,
+    io, mem,
+    os::unix::{
+        io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+        net::UnixStream,
+    },
+    ptr,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+/// Seals a ring must carry, so its size can not change while the server has it mapped.
+pub const SHM_RING_SEALS: libc::c_int = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW;
+
+/// Longest region id a record can hold, longer ids are truncated.
+pub const SHM_MAX_ID_LENGTH: usize = RECORD_SIZE - RECORD_ID_OFFSET;
+
+const MAGIC: u32 = u32::from_le_bytes(*b"THOR");
+const VERSION: u32 = 1;
+
+// Header layout. The producers' and the consumer's counters are kept on separate cache lines.
+const HEADER_SIZE: usize = 192;
+const MAGIC_OFFSET: usize = 0;
+const VERSION_OFFSET: usize = 4;
+const CAPACITY_OFFSET: usize = 8;
+const PROCESS_ID_OFFSET: usize = 12;
+const HEAD_OFFSET: usize = 64;
+const DROPPED_OFFSET: usize = 72;
+const TAIL_OFFSET: usize = 128;
+
+// Record layout
+const RECORD_SIZE: usize = 256;
+const RECORD_SEQUENCE_OFFSET: usize = 0;
+const RECORD_TIMESTAMP_OFFSET: usize = 8;
+const RECORD_THREAD_ID_OFFSET: usize = 24;
+const RECORD_WORK_COUNT_OFFSET: usize = 32;
+const RECORD_CPU_OFFSET: usize = 40;
+const RECORD_OPERATION_OFFSET: usize = 44;
+const RECORD_HAS_WORK_COUNT_OFFSET: usize = 45;
+const RECORD_ID_LENGTH_OFFSET: usize = 46;
+const RECORD_ID_OFFSET: usize = 48;
+
+// Name of the memory files, only shown in /proc
+const RING_NAME: &[u8] = b"thor-ring\0";
+
+// Stored in place of the CPU when it is unknown
+const NO_CPU: u32 = u32::MAX;
+
+/// A marker as stored in a ring, which holds everything but the attributes of a packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MarkerRecord {
+    pub id: String,
+    pub thread_id: usize,
+    pub operation: ProcessUnderTestPacketOperation,
+    pub timestamp: u128,
+    pub cpu: Option<u32>,
+    pub work_count: Option<u64>,
+}
+
+/// A ring of fixed-size marker records in sealed anonymous shared memory, written by the threads
+/// of one process under test and read by the server, which gets it over the Unix socket.
+///
+/// Writers reserve a record by advancing the head and publish it by setting its sequence number,
+/// so no locks or system calls are needed. Records are dropped and counted when the ring is full.
+pub struct ShmRing {
+    memory: *mut u8,
+    length: usize,
+    capacity: u64,
+    file: File,
+}
+
+// The ring only accesses the shared memory through atomics and published records
+unsafe impl Send for ShmRing {}
+unsafe impl Sync for ShmRing {}
+
+impl ShmRing {
+    /// Create the ring of a process, sealed so that neither side can resize it.
+    pub fn create(process_id: u32, capacity: u32) -> io::Result<ShmRing> {
+        let capacity = capacity.max(2);
+        // Safety: the name is a valid C string
+        let fd = unsafe {
+            libc::memfd_create(
+                RING_NAME.as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: memfd_create returned a new descriptor nothing else owns
+        let file = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        let length = HEADER_SIZE + capacity as usize * RECORD_SIZE;
+        file.set_len(length as u64)?;
+        // Further seals are refused as well, the ring is only ever read and written
+        if unsafe {
+            libc::fcntl(
+                file.as_raw_fd(),
+                libc::F_ADD_SEALS,
+                SHM_RING_SEALS | libc::F_SEAL_SEAL,
+            )
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut ring = ShmRing::map(file, length)?;
+        ring.capacity = capacity as u64;
+        ring.header_u32(CAPACITY_OFFSET)
+            .store(capacity, Ordering::Relaxed);
+        ring.header_u32(PROCESS_ID_OFFSET)
+            .store(process_id, Ordering::Relaxed);
+        ring.header_u32(VERSION_OFFSET)
+            .store(VERSION, Ordering::Relaxed);
+        // The magic is written last, readers refuse the ring until it is there
+        ring.header_u32(MAGIC_OFFSET)
+            .store(MAGIC, Ordering::Release);
+
+        Ok(ring)
+    }
+
+    /// Map a ring received from a process under test. Rings that can still be resized are refused,
+    /// as reading a ring truncated below its mapping would crash the reader.
+    pub fn from_fd(fd: OwnedFd) -> io::Result<ShmRing> {
+        let seals = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GET_SEALS) };
+        if seals < 0 || seals & SHM_RING_SEALS != SHM_RING_SEALS {
+            return Err(invalid_ring("ring is not sealed against resizing"));
+        }
+        let file = File::from(fd);
+        let length = file.metadata()?.len() as usize;
+        if length < HEADER_SIZE {
+            return Err(invalid_ring("ring is smaller than its header"));
+        }
+
+        let mut ring = ShmRing::map(file, length)?;
+        if ring.header_u32(MAGIC_OFFSET).load(Ordering::Acquire) != MAGIC {
+            return Err(invalid_ring("ring is not initialized"));
+        }
+        if ring.header_u32(VERSION_OFFSET).load(Ordering::Relaxed) != VERSION {
+            return Err(invalid_ring("unsupported ring version"));
+        }
+        ring.capacity = ring.header_u32(CAPACITY_OFFSET).load(Ordering::Relaxed) as u64;
+        if ring.capacity == 0 || HEADER_SIZE + ring.capacity as usize * RECORD_SIZE > length {
+            return Err(invalid_ring("ring is smaller than its capacity"));
+        }
+
+        Ok(ring)
+    }
+
+    fn map(file: File, length: usize) -> io::Result<ShmRing> {
+        let memory = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                length,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if memory == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ShmRing {
+            memory: memory as *mut u8,
+            length,
+            capacity: 0,
+            file,
+        })
+    }
+
+    pub fn process_id(&self) -> u32 {
+        self.header_u32(PROCESS_ID_OFFSET).load(Ordering::Relaxed)
+    }
+
+    /// Number of records dropped because the ring was full.
+    pub fn dropped(&self) -> u64 {
+        self.header_u64(DROPPED_OFFSET).load(Ordering::Relaxed)
+    }
+
+    /// Write a record, returning false if the ring is full and it was dropped.
+    pub fn push(&self, record: &MarkerRecord) -> bool {
+        let head = self.header_u64(HEAD_OFFSET);
+        let tail = self.header_u64(TAIL_OFFSET);
+
+        // Reserve the record at the head, unless the reader has not read it since the last lap
+        let mut index = head.load(Ordering::Relaxed);
+        loop {
+            // A stale index can be behind the tail, so the ring is only full if the head is current
+            if index.wrapping_sub(tail.load(Ordering::Acquire)) >= self.capacity {
+                let current = head.load(Ordering::Relaxed);
+                if current != index {
+                    index = current;
+                    continue;
+                }
+                self.header_u64(DROPPED_OFFSET)
+                    .fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            match head.compare_exchange_weak(index, index + 1, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(current) => index = current,
+            }
+        }
+
+        let id = truncate_id(&record.id);
+        unsafe {
+            let slot = self.record(index);
+            write(
+                slot,
+                RECORD_TIMESTAMP_OFFSET,
+                record.timestamp.to_le_bytes(),
+            );
+            write(
+                slot,
+                RECORD_THREAD_ID_OFFSET,
+                (record.thread_id as u64).to_le_bytes(),
+            );
+            write(
+                slot,
+                RECORD_WORK_COUNT_OFFSET,
+                record.work_count.unwrap_or(0).to_le_bytes(),
+            );
+            write(
+                slot,
+                RECORD_CPU_OFFSET,
+                record.cpu.unwrap_or(NO_CPU).to_le_bytes(),
+            );
+            write(
+                slot,
+                RECORD_OPERATION_OFFSET,
+                [operation_to_byte(record.operation)],
+            );
+            write(
+                slot,
+                RECORD_HAS_WORK_COUNT_OFFSET,
+                [record.work_count.is_some() as u8],
+            );
+            write(
+                slot,
+                RECORD_ID_LENGTH_OFFSET,
+                (id.len() as u16).to_le_bytes(),
+            );
+            ptr::copy_nonoverlapping(id.as_ptr(), slot.add(RECORD_ID_OFFSET), id.len());
+
+            // Publish the record to the reader
+            sequence(slot).store(index + 1, Ordering::Release);
+        }
+        true
+    }
+
+    /// Read the oldest unread record, if it has been published. Only one reader may pop at a time.
+    pub fn pop(&self) -> Option<MarkerRecord> {
+        let tail = self.header_u64(TAIL_OFFSET);
+        let index = tail.load(Ordering::Relaxed);
+
+        unsafe {
+            let slot = self.record(index);
+            if sequence(slot).load(Ordering::Acquire) != index + 1 {
+                return None;
+            }
+
+            let id_length = (u16::from_le_bytes(read(slot, RECORD_ID_LENGTH_OFFSET)) as usize)
+                .min(SHM_MAX_ID_LENGTH);
+            let mut id = vec![0; id_length];
+            ptr::copy_nonoverlapping(slot.add(RECORD_ID_OFFSET), id.as_mut_ptr(), id_length);
+            let [operation] = read(slot, RECORD_OPERATION_OFFSET);
+            let [has_work_count] = read(slot, RECORD_HAS_WORK_COUNT_OFFSET);
+            let cpu = u32::from_le_bytes(read(slot, RECORD_CPU_OFFSET));
+
+            let record = MarkerRecord {
+                id: String::from_utf8_lossy(&id).into_owned(),
+                thread_id: u64::from_le_bytes(read(slot, RECORD_THREAD_ID_OFFSET)) as usize,
+                operation: operation_from_byte(operation),
+                timestamp: u128::from_le_bytes(read(slot, RECORD_TIMESTAMP_OFFSET)),
+                cpu: (cpu != NO_CPU).then_some(cpu),
+                work_count: (has_work_count != 0)
+                    .then(|| u64::from_le_bytes(read(slot, RECORD_WORK_COUNT_OFFSET))),
+            };
+
+            // Hand the record back to the writers
+            tail.store(index + 1, Ordering::Release);
+            Some(record)
+        }
+    }
+
+    fn header_u32(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*(self.memory.add(offset) as *const AtomicU32) }
+    }
+
+    fn header_u64(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*(self.memory.add(offset) as *const AtomicU64) }
+    }
+
+    fn record(&self, index: u64) -> *mut u8 {
+        let position = (index % self.capacity) as usize;
+        unsafe { self.memory.add(HEADER_SIZE + position * RECORD_SIZE) }
+    }
+}
+
+impl AsFd for ShmRing {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl Drop for ShmRing {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.memory as *mut libc::c_void, self.length);
+        }
+    }
+}
+
+/// Pass a descriptor over a Unix socket, along with a single byte.
+pub fn send_fd(socket: &UnixStream, fd: BorrowedFd) -> io::Result<()> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: byte.len(),
+    };
+    // Aligned for the control message header
+    let mut control = [0u64; 4];
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    unsafe {
+        message.msg_controllen = libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as u32) as _;
+        let header = libc::CMSG_FIRSTHDR(&message);
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_RIGHTS;
+        (*header).cmsg_len = libc::CMSG_LEN(mem::size_of::<libc::c_int>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(header) as *mut libc::c_int, fd.as_raw_fd());
+    }
+
+    if unsafe { libc::sendmsg(socket.as_raw_fd(), &message, libc::MSG_NOSIGNAL) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive a descriptor sent with [`send_fd`].
+pub fn receive_fd(socket: &UnixStream) -> io::Result<OwnedFd> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: byte.len(),
+    };
+    let mut control = [0u64; 4];
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = mem::size_of_val(&control) as _;
+
+    let received =
+        unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, libc::MSG_CMSG_CLOEXEC) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if received == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let mut fd = None;
+    unsafe {
+        let mut header = libc::CMSG_FIRSTHDR(&message);
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+                // Every descriptor received is owned here, only the first one is kept
+                let data = libc::CMSG_DATA(header) as *const libc::c_int;
+                let count = ((*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / mem::size_of::<libc::c_int>();
+                for i in 0..count {
+                    let received = OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i)));
+                    fd.get_or_insert(received);
+                }
+            }
+            header = libc::CMSG_NXTHDR(&message, header);
+        }
+    }
+    if message.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(invalid_ring("more descriptors were sent than expected"));
+    }
+    fd.ok_or_else(|| invalid_ring("no descriptor was sent"))
+}
+
+fn invalid_ring(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Cut the id at a character boundary so it fits in a record
+fn truncate_id(id: &str) -> &str {
+    if id.len() <= SHM_MAX_ID_LENGTH {
+        return id;
+    }
+    let mut end = SHM_MAX_ID_LENGTH;
+    while !id.is_char_boundary(end) {
+        end -= 1;
+    }
+    &id[..end]
+}
+
+unsafe fn sequence<'a>(slot: *mut u8) -> &'a AtomicU64 {
+    &*(slot.add(RECORD_SEQUENCE_OFFSET) as *const AtomicU64)
+}
+
+unsafe fn write<const N: usize>(slot: *mut u8, offset: usize, bytes: [u8; N]) {
+    ptr::copy_nonoverlapping(bytes.as_ptr(), slot.add(offset), N);
+}
+
+unsafe fn read<const N: usize>(slot: *mut u8, offset: usize) -> [u8; N] {
+    let mut bytes = [0; N];
+    ptr::copy_nonoverlapping(slot.add(offset), bytes.as_mut_ptr(), N);
+    bytes
+}
+
+fn operation_to_byte(operation: ProcessUnderTestPacketOperation) -> u8 {
+    match operation {
+        ProcessUnderTestPacketOperation::Start => 0,
+        ProcessUnderTestPacketOperation::Stop => 1,
+        ProcessUnderTestPacketOperation::Mark => 2,
+    }
+}
+
+fn operation_from_byte(operation: u8) -> ProcessUnderTestPacketOperation {
+    match operation {
+        0 => ProcessUnderTestPacketOperation::Start,
+        1 => ProcessUnderTestPacketOperation::Stop,
+        _ => ProcessUnderTestPacketOperation::Mark,
+    }
+}
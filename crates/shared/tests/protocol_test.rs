@@ -0,0 +1,248 @@
+use thor_lib::{AmdRaplRegistersJoules, RaplMeasurementJoules};
+use thor_shared::{
+    protocol::{
+        check_handshake, decode_frame_length, decode_message, encode_handshake, encode_message,
+        parse_text_marker, ClientEncoding, FrameError, RegionTable, TextMarkerError,
+        FRAME_HEADER_LENGTH, MAX_FRAME_LENGTH, PROTOCOL_VERSION,
+    },
+    ClientMessage, MarkEvent, MarkerPacket, ProcessUnderTestMessage,
+    ProcessUnderTestPacketOperation,
+};
+
+fn registration(handle: u32, id: &str) -> ProcessUnderTestMessage {
+    ProcessUnderTestMessage::RegisterRegion {
+        handle,
+        id: id.to_string(),
+    }
+}
+
+fn marker(region: u32) -> ProcessUnderTestMessage {
+    ProcessUnderTestMessage::Marker(MarkerPacket {
+        region,
+        process_id: 42,
+        thread_id: 7,
+        operation: ProcessUnderTestPacketOperation::Start,
+        timestamp: 1_700_000_000_000_000_000,
+        cpu: Some(3),
+        work_count: None,
+        attributes: vec![("size".to_string(), "1024".to_string())],
+        sequence: 9,
+    })
+}
+
+// Splits a frame into its header and payload, as the server reads it
+fn decode_frame(frame: &[u8]) -> Result<ProcessUnderTestMessage, FrameError> {
+    let header = frame[..FRAME_HEADER_LENGTH].try_into().unwrap();
+    let length = decode_frame_length(header)?;
+    decode_message(&frame[FRAME_HEADER_LENGTH..FRAME_HEADER_LENGTH + length])
+}
+
+#[test]
+fn test_handshake_versions() {
+    assert_eq!(encode_handshake(), PROTOCOL_VERSION.to_le_bytes());
+    assert!(check_handshake(encode_handshake()).is_ok());
+    assert!(matches!(
+        check_handshake((PROTOCOL_VERSION + 1).to_le_bytes()),
+        Err(FrameError::UnsupportedVersion(_))
+    ));
+}
+
+#[test]
+fn test_frame_round_trip() {
+    let mut region_table = RegionTable::default();
+    let frame = encode_message(&registration(0, "sort")).unwrap();
+    assert!(region_table
+        .resolve(decode_frame(&frame).unwrap())
+        .unwrap()
+        .is_none());
+
+    let frame = encode_message(&marker(0)).unwrap();
+    let decoded = region_table
+        .resolve(decode_frame(&frame).unwrap())
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(decoded.id, "sort");
+    assert_eq!(decoded.process_id, 42);
+    assert_eq!(decoded.thread_id, 7);
+    assert_eq!(decoded.operation, ProcessUnderTestPacketOperation::Start);
+    assert_eq!(decoded.cpu, Some(3));
+    assert_eq!(decoded.sequence, Some(9));
+    assert_eq!(
+        decoded.attributes,
+        [("size".to_string(), "1024".to_string())]
+    );
+}
+
+#[test]
+fn test_packets_longer_than_255_bytes() {
+    let id = "region".repeat(100);
+    let frame = encode_message(&registration(0, &id)).unwrap();
+    assert!(frame.len() > u8::MAX as usize);
+
+    let mut region_table = RegionTable::default();
+    region_table.resolve(decode_frame(&frame).unwrap()).unwrap();
+    let packet = region_table.resolve(marker(0)).unwrap().unwrap();
+    assert_eq!(packet.id, id);
+}
+
+#[test]
+fn test_frame_layout() {
+    // The header is the payload length as a little endian u32, followed by the bincode encoded message
+    let frame = encode_message(&marker(5)).unwrap();
+    let length = u32::from_le_bytes(frame[..4].try_into().unwrap()) as usize;
+    assert_eq!(length, frame.len() - FRAME_HEADER_LENGTH);
+    // Markers are the second variant and carry the region handle instead of the id
+    assert_eq!(&frame[4..8], &1u32.to_le_bytes());
+    assert_eq!(&frame[8..12], &5u32.to_le_bytes());
+}
+
+#[test]
+fn test_invalid_frames_are_errors() {
+    let too_long = ((MAX_FRAME_LENGTH + 1) as u32).to_le_bytes();
+    assert!(matches!(
+        decode_frame_length(too_long),
+        Err(FrameError::TooLong(_))
+    ));
+
+    let frame = encode_message(&marker(0)).unwrap();
+    assert!(decode_message(&frame[FRAME_HEADER_LENGTH..frame.len() - 1]).is_err());
+
+    let mut trailing = frame[FRAME_HEADER_LENGTH..].to_vec();
+    trailing.push(0);
+    assert!(decode_message(&trailing).is_err());
+
+    assert!(decode_message(&[0xff; 16]).is_err());
+}
+
+#[test]
+fn test_unregistered_region_handles() {
+    let mut region_table = RegionTable::default();
+    assert!(matches!(
+        region_table.resolve(marker(3)),
+        Err(FrameError::UnknownRegion(3))
+    ));
+
+    // Handles belong to the connection they were registered on
+    region_table.resolve(registration(3, "sort")).unwrap();
+    assert!(RegionTable::default().resolve(marker(3)).is_err());
+    assert_eq!(region_table.resolve(marker(3)).unwrap().unwrap().id, "sort");
+}
+
+fn mark_message() -> ClientMessage {
+    ClientMessage::Mark(MarkEvent {
+        id: "cache warmed".to_string(),
+        attributes: vec![("phase".to_string(), "end".to_string())],
+        process_id: 42,
+        thread_id: 7,
+        timestamp: 1_700_000_000_000_000_000,
+        cpu: None,
+        rapl_measurement: RaplMeasurementJoules::AMD(AmdRaplRegistersJoules {
+            core: 1.5,
+            pkg: 3.25,
+        }),
+        pkg_overflow: 1,
+        parent_path: vec!["main".to_string()],
+    })
+}
+
+#[test]
+fn test_client_frames_in_every_encoding() {
+    for encoding in [
+        ClientEncoding::Json,
+        ClientEncoding::Bincode,
+        ClientEncoding::MessagePack,
+    ] {
+        let frame = encoding.encode_frame(&mark_message()).unwrap();
+        let header = frame[..FRAME_HEADER_LENGTH].try_into().unwrap();
+        let length = decode_frame_length(header).unwrap();
+        assert_eq!(length, frame.len() - FRAME_HEADER_LENGTH);
+
+        // Strings containing the old "end" delimiter survive
+        let decoded: ClientMessage = encoding.decode(&frame[FRAME_HEADER_LENGTH..]).unwrap();
+        let ClientMessage::Mark(mark) = decoded else {
+            panic!("Decoded another message with {:?}", encoding);
+        };
+        assert_eq!(mark.attributes[0].1, "end");
+        assert_eq!(mark.timestamp, 1_700_000_000_000_000_000);
+        assert_eq!(mark.rapl_measurement.pkg(), 3.25);
+    }
+}
+
+#[test]
+fn test_encoding_names() {
+    assert_eq!(
+        ClientEncoding::from_name("json"),
+        Some(ClientEncoding::Json)
+    );
+    assert_eq!(
+        ClientEncoding::from_name("bincode"),
+        Some(ClientEncoding::Bincode)
+    );
+    assert_eq!(
+        ClientEncoding::from_name("msgpack"),
+        Some(ClientEncoding::MessagePack)
+    );
+    assert_eq!(ClientEncoding::from_name("xml"), None);
+}
+
+#[test]
+fn test_text_markers() {
+    let start = parse_text_marker("start build", 100).unwrap().unwrap();
+    assert_eq!(start.id, "build");
+    assert_eq!(start.operation, ProcessUnderTestPacketOperation::Start);
+    assert_eq!((start.process_id, start.thread_id), (0, 0));
+    assert_eq!(start.timestamp, 100);
+
+    let stop = parse_text_marker("STOP build 1234 5678 @42\r", 100)
+        .unwrap()
+        .unwrap();
+    assert_eq!(stop.operation, ProcessUnderTestPacketOperation::Stop);
+    assert_eq!((stop.process_id, stop.thread_id), (1234, 5678));
+    assert_eq!(stop.timestamp, 42);
+
+    // The timestamp can be given without the ids
+    let mark = parse_text_marker("mark tests-passed @7", 100)
+        .unwrap()
+        .unwrap();
+    assert_eq!(mark.operation, ProcessUnderTestPacketOperation::Mark);
+    assert_eq!(mark.process_id, 0);
+    assert_eq!(mark.timestamp, 7);
+
+    assert!(parse_text_marker("  ", 100).unwrap().is_none());
+}
+
+#[test]
+fn test_invalid_text_markers() {
+    assert_eq!(
+        parse_text_marker("begin build", 0).unwrap_err(),
+        TextMarkerError::UnknownOperation("begin".to_string())
+    );
+    assert_eq!(
+        parse_text_marker("start", 0).unwrap_err(),
+        TextMarkerError::MissingId
+    );
+    assert!(matches!(
+        parse_text_marker("start build abc", 0),
+        Err(TextMarkerError::InvalidNumber { .. })
+    ));
+    assert!(matches!(
+        parse_text_marker("start build 99999999999", 0),
+        Err(TextMarkerError::InvalidNumber { .. })
+    ));
+    assert_eq!(
+        parse_text_marker("start build 1 99999999999999999999", 0).unwrap_err(),
+        TextMarkerError::InvalidNumber {
+            field: "thread id",
+            value: "99999999999999999999".to_string()
+        }
+    );
+    assert_eq!(
+        parse_text_marker("stop build 1 2 3", 0).unwrap_err(),
+        TextMarkerError::TrailingField("3".to_string())
+    );
+    assert_eq!(
+        parse_text_marker("stop build @1 2", 0).unwrap_err(),
+        TextMarkerError::TrailingField("2".to_string())
+    );
+}
@@ -0,0 +1,201 @@
+#![cfg(target_os = "linux")]
+
+use std::{
+    fs::File,
+    os::unix::{
+        io::{AsFd, FromRawFd, OwnedFd},
+        net::UnixStream,
+    },
+    sync::Arc,
+    thread,
+};
+use thor_shared::{
+    shm_ring::{receive_fd, send_fd, MarkerRecord, ShmRing, SHM_MAX_ID_LENGTH},
+    ProcessUnderTestPacketOperation,
+};
+
+// A second mapping of the ring, as the server gets it
+fn open_reader(ring: &ShmRing) -> ShmRing {
+    ShmRing::from_fd(ring.as_fd().try_clone_to_owned().unwrap()).unwrap()
+}
+
+fn record(id: &str, thread_id: usize) -> MarkerRecord {
+    MarkerRecord {
+        id: id.to_string(),
+        thread_id,
+        operation: ProcessUnderTestPacketOperation::Stop,
+        timestamp: 1_700_000_000_000_000_000,
+        cpu: Some(3),
+        work_count: Some(1024),
+    }
+}
+
+#[test]
+fn test_records_round_trip() {
+    let writer = ShmRing::create(42, 8).unwrap();
+    let reader = open_reader(&writer);
+    assert_eq!(reader.process_id(), 42);
+
+    assert!(writer.push(&record("sort", 7)));
+    let mut start = record("merge", 8);
+    start.operation = ProcessUnderTestPacketOperation::Start;
+    start.cpu = None;
+    start.work_count = None;
+    assert!(writer.push(&start));
+
+    assert_eq!(reader.pop(), Some(record("sort", 7)));
+    assert_eq!(reader.pop(), Some(start));
+    assert_eq!(reader.pop(), None);
+}
+
+#[test]
+fn test_full_ring_drops_and_counts() {
+    let writer = ShmRing::create(42, 4).unwrap();
+    let reader = open_reader(&writer);
+
+    for i in 0..6 {
+        assert_eq!(writer.push(&record(&i.to_string(), 1)), i < 4);
+    }
+    assert_eq!(reader.dropped(), 2);
+
+    // Reading frees records for the writers, wrapping around the ring
+    assert_eq!(reader.pop().unwrap().id, "0");
+    assert!(writer.push(&record("4", 1)));
+    let ids: Vec<String> = std::iter::from_fn(|| reader.pop())
+        .map(|record| record.id)
+        .collect();
+    assert_eq!(ids, ["1", "2", "3", "4"]);
+}
+
+#[test]
+fn test_long_ids_are_truncated() {
+    let ring = ShmRing::create(42, 4).unwrap();
+
+    ring.push(&record(&"é".repeat(SHM_MAX_ID_LENGTH), 1));
+    let id = ring.pop().unwrap().id;
+    assert!(id.len() <= SHM_MAX_ID_LENGTH);
+    assert!(id.chars().all(|c| c == 'é'));
+}
+
+#[test]
+fn test_concurrent_writers() {
+    let writer = Arc::new(ShmRing::create(42, 4096).unwrap());
+    let reader = open_reader(&writer);
+
+    let writers: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let writer = writer.clone();
+            thread::spawn(move || {
+                for i in 0..500 {
+                    assert!(writer.push(&record(&i.to_string(), thread_id)));
+                }
+            })
+        })
+        .collect();
+
+    // Records of each thread are read in the order they were written
+    let mut next = [0; 4];
+    let mut read = 0;
+    while read < 2000 {
+        match reader.pop() {
+            Some(record) => {
+                assert_eq!(record.id, next[record.thread_id].to_string());
+                next[record.thread_id] += 1;
+                read += 1;
+            }
+            None => thread::yield_now(),
+        }
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    assert_eq!(reader.dropped(), 0);
+}
+
+#[test]
+fn test_concurrent_writers_wrapping_around() {
+    let writer = Arc::new(ShmRing::create(42, 8).unwrap());
+    let reader = open_reader(&writer);
+
+    // The ring laps many times while it is read, writers retry the records dropped while it is full
+    let writers: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let writer = writer.clone();
+            thread::spawn(move || {
+                for i in 0..2000 {
+                    while !writer.push(&record(&i.to_string(), thread_id)) {
+                        thread::yield_now();
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let mut next = [0; 4];
+    let mut read = 0;
+    while read < 8000 {
+        match reader.pop() {
+            Some(record) => {
+                assert_eq!(record.id, next[record.thread_id].to_string());
+                next[record.thread_id] += 1;
+                read += 1;
+            }
+            None => thread::yield_now(),
+        }
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    assert_eq!(reader.pop(), None);
+}
+
+#[test]
+fn test_rings_are_sealed_against_resizing() {
+    let ring = ShmRing::create(42, 4).unwrap();
+    let file = File::from(ring.as_fd().try_clone_to_owned().unwrap());
+    assert!(file.set_len(64).is_err());
+    assert!(file.set_len(1 << 20).is_err());
+}
+
+#[test]
+fn test_unsealed_and_uninitialized_rings_are_refused() {
+    const NAME: &[u8] = b"unsealed\0";
+    let fd = unsafe {
+        libc::memfd_create(
+            NAME.as_ptr() as *const libc::c_char,
+            libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+        )
+    };
+    assert!(fd >= 0);
+    let file = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    file.set_len(4096).unwrap();
+    assert!(ShmRing::from_fd(file.try_clone().unwrap().into()).is_err());
+
+    // Sealed but never initialized
+    assert!(
+        unsafe {
+            libc::fcntl(
+                fd,
+                libc::F_ADD_SEALS,
+                libc::F_SEAL_SHRINK | libc::F_SEAL_GROW,
+            )
+        } == 0
+    );
+    assert!(ShmRing::from_fd(file.into()).is_err());
+}
+
+#[test]
+fn test_rings_are_passed_over_unix_sockets() {
+    let (client, server) = UnixStream::pair().unwrap();
+    let writer = ShmRing::create(42, 4).unwrap();
+    send_fd(&client, writer.as_fd()).unwrap();
+    let reader = ShmRing::from_fd(receive_fd(&server).unwrap()).unwrap();
+    assert_eq!(reader.process_id(), 42);
+
+    writer.push(&record("passed", 1));
+    assert_eq!(reader.pop().unwrap().id, "passed");
+
+    // Nothing is received once the sender closes its side
+    drop(client);
+    assert!(receive_fd(&server).is_err());
+}